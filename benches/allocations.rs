@@ -0,0 +1,170 @@
+//! Heap allocations per frame between the decoder and the publisher.
+//!
+//! Feeds synthetic 1280x720 yuv420p frames, as the H.264 decoder puts them
+//! out, through the path frames used to take: video-rs scaled them to rgb24,
+//! copied that into a freshly allocated array, and the filter graph converted
+//! the rgb24 copy once more. The pooled path converts the decoder frame once
+//! and the publisher hands each buffer back once the next frame replaces it.
+//!
+//!     cargo bench --no-default-features --bench allocations
+//!
+//! Only allocations through the Rust allocator are counted; ffmpeg's own,
+//! e.g. for the frames inside the filter graph, are not.
+
+use ffmpeg::format::Pixel;
+use ffmpeg::software::scaling;
+use ffmpeg::util::frame;
+use ffmpeg_next as ffmpeg;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use ros2_go2_video::encoding::Encoding;
+use ros2_go2_video::filter::Filter;
+use ros2_go2_video::pool::BufferPool;
+use ros2_go2_video::transform::Transform;
+
+const WIDTH: u32 = 1280;
+const HEIGHT: u32 = 720;
+const FRAMES: u64 = 300;
+
+struct Counting;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static BYTES: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(new_size as u64, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn main() {
+    ffmpeg::init().unwrap();
+    let input = test_frame();
+    let resized = Transform {
+        width: 640,
+        height: 360,
+        ..Transform::default()
+    };
+
+    println!(
+        "{} frames of {}x{} yuv420p, Rust heap allocations per frame\n",
+        FRAMES, WIDTH, HEIGHT
+    );
+    println!("{:<18} {:>14} {:>14} {:>10}", "", "allocations", "kB", "ms");
+    for (name, encoding, transform) in [
+        ("rgb8", Encoding::Rgb8, Transform::default()),
+        ("bgr8", Encoding::Bgr8, Transform::default()),
+        ("rgb8 640x360", Encoding::Rgb8, resized),
+    ] {
+        // Before: scaled to rgb24 the way video-rs does, copied into a new
+        // buffer, and the buffer published last was dropped with its message
+        let mut scaler = scaling::Context::get(
+            Pixel::YUV420P,
+            WIDTH,
+            HEIGHT,
+            Pixel::RGB24,
+            WIDTH,
+            HEIGHT,
+            scaling::Flags::AREA,
+        )
+        .unwrap();
+        let mut scaled = frame::Video::empty();
+        let mut filter = Filter::new(encoding, &transform, BufferPool::new());
+        report(&format!("{} before", name), || {
+            scaler.run(&input, &mut scaled).unwrap();
+            let rgb = packed(&scaled);
+            if encoding == Encoding::Rgb8 && transform.is_identity() {
+                return rgb;
+            }
+            filter.run(&unpacked(&rgb)).unwrap().0
+        });
+
+        let pool = BufferPool::new();
+        let mut filter = Filter::new(encoding, &transform, pool.clone());
+        let mut published = Vec::new();
+        report(&format!("{} pooled", name), || {
+            let (data, _, _) = filter.run(&input).unwrap();
+            pool.put(std::mem::replace(&mut published, data));
+            Vec::new()
+        });
+    }
+}
+
+/// Runs `frame` [`FRAMES`] times after a warm-up and prints the averages.
+fn report(name: &str, mut frame: impl FnMut() -> Vec<u8>) {
+    for _ in 0..3 {
+        drop(frame());
+    }
+    let (allocations, bytes) = (
+        ALLOCATIONS.load(Ordering::Relaxed),
+        BYTES.load(Ordering::Relaxed),
+    );
+    let started = Instant::now();
+    for _ in 0..FRAMES {
+        drop(frame());
+    }
+    let elapsed = started.elapsed();
+    println!(
+        "{:<18} {:>14.2} {:>14.1} {:>10.2}",
+        name,
+        (ALLOCATIONS.load(Ordering::Relaxed) - allocations) as f64 / FRAMES as f64,
+        (BYTES.load(Ordering::Relaxed) - bytes) as f64 / FRAMES as f64 / 1000.0,
+        elapsed.as_secs_f64() * 1000.0 / FRAMES as f64,
+    );
+}
+
+/// A gradient, so the filters have something to work on.
+fn test_frame() -> frame::Video {
+    let mut frame = frame::Video::new(Pixel::YUV420P, WIDTH, HEIGHT);
+    for plane in 0..3 {
+        let (width, height) = (frame.plane_width(plane), frame.plane_height(plane));
+        let stride = frame.stride(plane);
+        let data = frame.data_mut(plane);
+        for y in 0..height as usize {
+            for x in 0..width as usize {
+                data[y * stride + x] = (x + y * plane) as u8;
+            }
+        }
+    }
+    frame
+}
+
+/// The rgb24 copy back in an ffmpeg frame, for the filter graph.
+fn unpacked(rgb: &[u8]) -> frame::Video {
+    let mut frame = frame::Video::new(Pixel::RGB24, WIDTH, HEIGHT);
+    let row = WIDTH as usize * 3;
+    let stride = frame.stride(0);
+    let data = frame.data_mut(0);
+    for (y, line) in rgb.chunks_exact(row).enumerate() {
+        data[y * stride..][..row].copy_from_slice(line);
+    }
+    frame
+}
+
+/// The copy video-rs made of every decoded frame, without row padding.
+fn packed(frame: &frame::Video) -> Vec<u8> {
+    let row = frame.width() as usize * 3;
+    let stride = frame.stride(0);
+    let mut rgb = Vec::with_capacity(row * frame.height() as usize);
+    for y in 0..frame.height() as usize {
+        rgb.extend_from_slice(&frame.data(0)[y * stride..][..row]);
+    }
+    rgb
+}
@@ -0,0 +1,112 @@
+//! Opus decoding of the robot's audio track into interleaved 16-bit PCM.
+//!
+//! The audio stream is opened from its SDP file like the video, see [`crate::decode`].
+
+use anyhow::{anyhow, Result};
+use ffmpeg::format::sample::{Sample, Type as SampleType};
+use ffmpeg::software::resampling;
+use ffmpeg_next as ffmpeg;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Format of the decoded PCM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PcmFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl PcmFormat {
+    /// Sample format name as used by `audio_common_msgs/AudioInfo`.
+    pub const SAMPLE_FORMAT: &'static str = "S16LE";
+}
+
+/// A chunk of interleaved S16LE samples.
+pub struct PcmChunk {
+    pub format: PcmFormat,
+    /// Presentation time in seconds, if the stream carries one
+    pub time: Option<f64>,
+    pub data: Vec<u8>,
+}
+
+/// Decodes the audio stream described by `sdp` until it ends.
+///
+/// Blocks the calling thread; `on_chunk` is called for every decoded frame.
+pub fn decode(
+    logger: &str,
+    sdp: &Path,
+    options: &HashMap<String, String>,
+    mut on_chunk: impl FnMut(PcmChunk),
+) -> Result<()> {
+    let mut dictionary = ffmpeg::Dictionary::new();
+    for (key, value) in options {
+        dictionary.set(key, value);
+    }
+
+    let mut input = ffmpeg::format::input_with_dictionary(&sdp, dictionary)?;
+    let stream = input
+        .streams()
+        .best(ffmpeg::media::Type::Audio)
+        .ok_or_else(|| anyhow!("no audio stream in {}", sdp.display()))?;
+    let stream_index = stream.index();
+    let time_base = f64::from(stream.time_base());
+
+    let context = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?;
+    let mut decoder = context.decoder().audio()?;
+
+    let packed_s16 = Sample::I16(SampleType::Packed);
+    let mut resampler: Option<resampling::Context> = None;
+    let mut frame = ffmpeg::frame::Audio::empty();
+    let mut pcm = ffmpeg::frame::Audio::empty();
+
+    loop {
+        // Reading ends when the relay closes the stream with an RTCP BYE
+        let mut packet = ffmpeg::Packet::empty();
+        match packet.read(&mut input) {
+            Ok(()) => {}
+            Err(ffmpeg::Error::Eof) => break,
+            Err(e) => return Err(e.into()),
+        }
+        if packet.stream() != stream_index {
+            continue;
+        }
+        // A damaged packet costs a few milliseconds of audio, not the stream
+        if let Err(e) = decoder.send_packet(&packet) {
+            log_warn!(
+                logger,
+                "Skipped an audio packet the decoder rejected: {}",
+                e
+            );
+            continue;
+        }
+
+        while decoder.receive_frame(&mut frame).is_ok() {
+            // The decoder only knows its output format once it produced a frame
+            let resampler = match &mut resampler {
+                Some(resampler) => resampler,
+                None => resampler.insert(resampling::Context::get(
+                    frame.format(),
+                    frame.channel_layout(),
+                    frame.rate(),
+                    packed_s16,
+                    frame.channel_layout(),
+                    frame.rate(),
+                )?),
+            };
+            resampler.run(&frame, &mut pcm)?;
+
+            let format = PcmFormat {
+                sample_rate: pcm.rate(),
+                channels: pcm.channels(),
+            };
+            let len = pcm.samples() * format.channels as usize * 2;
+            on_chunk(PcmChunk {
+                format,
+                time: frame.pts().map(|pts| pts as f64 * time_base),
+                data: pcm.data(0)[..len].to_vec(),
+            });
+        }
+    }
+
+    Ok(())
+}
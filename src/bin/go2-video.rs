@@ -0,0 +1,329 @@
+//! Command line tool to check and grab video from a Go2 without ROS.
+
+use anyhow::{anyhow, bail, Context, Result};
+use dotenv::dotenv;
+use futures::StreamExt;
+use image::codecs::jpeg::JpegEncoder;
+use image::{ColorType, ImageEncoder};
+use std::env;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tokio::time;
+
+use ros2_go2_video::webrtc::Traffic;
+use ros2_go2_video::{h264, record, Go2VideoSource};
+
+const USAGE: &str = "\
+Usage: go2-video <command> [options]
+
+Commands:
+  probe    connect and print codec, resolution, frame rate, bitrate, decode time and latency
+  snap     save a frame from the first keyframe on as JPEG (--output, default go2_snap.jpg)
+  record   write --duration seconds to MP4 or MKV (--output, default go2_video.mp4)
+
+Options:
+  --robot-ip <ip>             robot address, defaults to ROBOT_IP from the environment or .env
+  --robot-token <token>       robot token, defaults to ROBOT_TOKEN
+  --video-port <port>         local video RTP port (4002)
+  --audio-port <port>         local audio RTP port (4000)
+  --connect-timeout <secs>    wait this long for video (10)
+  --stall-timeout <secs>      give up when video stops this long (5)
+  --h264-profile-level-id <id>  profile announced to the decoder (42e01f)
+  --decoder-option <k=v>      ffmpeg option over the defaults, repeatable
+  --debug-webrtc              log the WebRTC signalling
+  --duration <secs>           how long probe measures and record records (5)
+  --output <path>             file written by snap and record
+  --jpeg-quality <1-100>      quality of snap (90)
+";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Command {
+    Probe,
+    Snap,
+    Record,
+}
+
+struct Args {
+    command: Command,
+    source: Go2VideoSource,
+    duration: Duration,
+    output: Option<PathBuf>,
+    jpeg_quality: u8,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args> {
+    let command = match args.next().as_deref() {
+        Some("probe") => Command::Probe,
+        Some("snap") => Command::Snap,
+        Some("record") => Command::Record,
+        Some(other) => bail!("unknown command '{}'", other),
+        None => bail!("no command given"),
+    };
+
+    let mut source = Go2VideoSource::new(env::var("ROBOT_IP").unwrap_or_default())
+        .robot_token(env::var("ROBOT_TOKEN").unwrap_or_default());
+    let mut duration = Duration::from_secs(5);
+    let mut output = None;
+    let mut jpeg_quality = 90;
+
+    while let Some(flag) = args.next() {
+        if flag == "--debug-webrtc" {
+            source = source.debug_webrtc(true);
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| anyhow!("{} needs a value", flag))?;
+        let seconds = |value: &str| -> Result<Duration> {
+            let secs: f64 = value.parse().with_context(|| format!("invalid {}", flag))?;
+            if !(secs > 0.0) {
+                bail!("{} must be positive, got {}", flag, secs);
+            }
+            Ok(Duration::from_secs_f64(secs))
+        };
+        match flag.as_str() {
+            "--robot-ip" => source = source.robot_ip(value),
+            "--robot-token" => source = source.robot_token(value),
+            "--video-port" => source = source.video_port(value.parse()?),
+            "--audio-port" => source = source.audio_port(value.parse()?),
+            "--connect-timeout" => source = source.connect_timeout(seconds(&value)?),
+            "--stall-timeout" => source = source.stall_timeout(seconds(&value)?),
+            "--h264-profile-level-id" => source = source.h264_profile_level_id(value),
+            "--decoder-option" => {
+                let (name, value) = value
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("decoder option '{}' is not key=value", value))?;
+                source = source.decoder_option(name, value);
+            }
+            "--duration" => duration = seconds(&value)?,
+            "--output" => output = Some(PathBuf::from(value)),
+            "--jpeg-quality" => {
+                jpeg_quality = value.parse().with_context(|| format!("invalid {}", flag))?;
+                if !(1..=100).contains(&jpeg_quality) {
+                    bail!("{} must be 1-100, got {}", flag, jpeg_quality);
+                }
+            }
+            _ => bail!("unknown option '{}'", flag),
+        }
+    }
+
+    Ok(Args {
+        command,
+        source: source.logger("go2-video"),
+        duration,
+        output,
+        jpeg_quality,
+    })
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenv().ok();
+    tracing_subscriber::fmt::init();
+    ffmpeg_next::init()?;
+
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{:#}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    match args.command {
+        Command::Probe => probe(args.source, args.duration).await,
+        Command::Snap => {
+            let output = args.output.unwrap_or_else(|| "go2_snap.jpg".into());
+            snap(args.source, &output, args.jpeg_quality).await
+        }
+        Command::Record => {
+            let output = args.output.unwrap_or_else(|| "go2_video.mp4".into());
+            record(args.source, &output, args.duration).await
+        }
+    }
+}
+
+/// Connects and measures the stream for `duration`.
+async fn probe(source: Go2VideoSource, duration: Duration) -> Result<()> {
+    let traffic = Arc::new(Traffic::default());
+    let started = Instant::now();
+    let mut video = source.traffic(traffic.clone()).start().await?;
+    video.wait_for_video().await?;
+    let connect_time = started.elapsed();
+
+    let mut access_units = video.session().access_units();
+    let mut frames = video.frames().await?;
+    let window = Instant::now();
+    let bytes_before = traffic.bytes.load(Ordering::Relaxed);
+    let deadline = time::sleep(duration);
+    tokio::pin!(deadline);
+
+    let mut sps = None;
+    let mut access_unit_count = 0u64;
+    let mut frame_count = 0u64;
+    let mut decode_time = Duration::ZERO;
+    let mut latency = Duration::ZERO;
+    let mut size = None;
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            access_unit = access_units.recv() => match access_unit {
+                Ok(access_unit) => {
+                    access_unit_count += 1;
+                    if sps.is_none() && access_unit.keyframe {
+                        sps = h264::parameter_sets(&access_unit)
+                            .and_then(|sets| h264::nal_units(&sets).first().map(|sps| sps.to_vec()));
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => bail!("session ended while probing"),
+            },
+            frame = frames.next() => match frame {
+                Some(frame) => {
+                    frame_count += 1;
+                    decode_time += frame.decoded_at.duration_since(frame.received_at);
+                    latency += frame.received_at.elapsed();
+                    size = Some((frame.width, frame.height));
+                }
+                None => bail!("decoder stopped while probing"),
+            },
+        }
+    }
+    let elapsed = window.elapsed().as_secs_f64();
+    let bytes = traffic.bytes.load(Ordering::Relaxed) - bytes_before;
+
+    match &sps {
+        // profile_idc and level_idc follow the NAL header
+        Some(sps) if sps.len() > 3 => println!(
+            "codec:       H.264 profile {} level {:.1}",
+            sps[1],
+            sps[3] as f64 / 10.0
+        ),
+        _ => println!("codec:       H.264 (no keyframe seen)"),
+    }
+    if let Some((width, height)) = sps.as_deref().and_then(h264::sps_dimensions).or(size) {
+        println!("resolution:  {}x{}", width, height);
+    }
+    println!(
+        "frame rate:  {:.1} fps received, {:.1} fps decoded",
+        access_unit_count as f64 / elapsed,
+        frame_count as f64 / elapsed
+    );
+    println!(
+        "bitrate:     {:.0} kbit/s",
+        bytes as f64 * 8.0 / elapsed / 1000.0
+    );
+    println!(
+        "connect:     {:.2} s to first video",
+        connect_time.as_secs_f64()
+    );
+    if frame_count > 0 {
+        // Starts when the decoder reads the frame's last packet from the relay,
+        // so the network and the robot's encoder are not included
+        println!(
+            "decode:      {:.1} ms per frame from the last packet read to the converted frame",
+            decode_time.as_secs_f64() * 1000.0 / frame_count as f64
+        );
+        println!(
+            "latency:     {:.1} ms per frame from the last packet read to the frame in hand",
+            latency.as_secs_f64() * 1000.0 / frame_count as f64
+        );
+    }
+    Ok(())
+}
+
+/// Saves the first frame decoded from a keyframe on as JPEG.
+async fn snap(source: Go2VideoSource, output: &Path, quality: u8) -> Result<()> {
+    let mut video = source.start().await?;
+    video.wait_for_video().await?;
+    let mut access_units = video.session().access_units();
+    let mut frames = video.frames().await?;
+
+    // Frames decoded before the first keyframe miss their references and come
+    // out grey or smeared
+    let mut keyframe_at = None;
+    let frame = loop {
+        tokio::select! {
+            access_unit = access_units.recv(), if keyframe_at.is_none() => match access_unit {
+                Ok(access_unit) if access_unit.keyframe => {
+                    keyframe_at = Some(access_unit.received_at);
+                }
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => {
+                    bail!("session ended before the first keyframe")
+                }
+            },
+            frame = frames.next() => {
+                let frame =
+                    frame.ok_or_else(|| anyhow!("decoder stopped before the first keyframe"))?;
+                if keyframe_at.is_some_and(|at| frame.received_at >= at) {
+                    break frame;
+                }
+            }
+        }
+    };
+
+    let file = BufWriter::new(File::create(output)?);
+    JpegEncoder::new_with_quality(file, quality).write_image(
+        &frame.data,
+        frame.width,
+        frame.height,
+        ColorType::Rgb8,
+    )?;
+    println!(
+        "{}x{} frame saved to {}",
+        frame.width,
+        frame.height,
+        output.display()
+    );
+    Ok(())
+}
+
+/// Records the encoded stream for `duration`, starting at the first keyframe.
+async fn record(source: Go2VideoSource, output: &Path, duration: Duration) -> Result<()> {
+    let extension = output.extension().and_then(|e| e.to_str()).unwrap_or("");
+    let settings = record::Settings {
+        directory: output.parent().map(Path::to_path_buf).unwrap_or_default(),
+        filename: output
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or_else(|| anyhow!("{} has no file name", output.display()))?
+            .to_string(),
+        container: record::Container::from_param(extension)?,
+        segment_duration: None,
+        segment_size: None,
+    };
+
+    let mut video = source.start().await?;
+    video.wait_for_video().await?;
+    let mut access_units = video.session().access_units();
+
+    let mut recorder = record::Recorder::new("go2-video".to_string());
+    let mut end = None;
+    loop {
+        let access_unit = match access_units.recv().await {
+            Ok(access_unit) => access_unit,
+            Err(broadcast::error::RecvError::Lagged(_)) => {
+                recorder.resync();
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        recorder.write(&access_unit, &settings)?;
+        // The recording starts at the first keyframe, and so does the clock
+        if recorder.path().is_some() && end.is_none() {
+            end = Some(access_unit.received_at + duration);
+        }
+        if end.is_some_and(|end| access_unit.received_at >= end) {
+            break;
+        }
+    }
+    recorder.stop()?;
+    println!("Recorded {}", output.display());
+    Ok(())
+}
@@ -0,0 +1,191 @@
+//! Camera calibration in the `camera_info_manager` YAML format.
+
+use anyhow::{bail, Context, Result};
+use r2r::sensor_msgs::msg::CameraInfo;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize)]
+struct Matrix {
+    rows: usize,
+    cols: usize,
+    data: Vec<f64>,
+}
+
+impl Matrix {
+    fn new(rows: usize, cols: usize, data: &[f64]) -> Matrix {
+        Matrix {
+            rows,
+            cols,
+            data: data.to_vec(),
+        }
+    }
+
+    fn into_data(self, name: &str) -> Result<Vec<f64>> {
+        if self.data.len() != self.rows * self.cols {
+            bail!(
+                "{} has {} values, expected {}x{}",
+                name,
+                self.data.len(),
+                self.rows,
+                self.cols
+            );
+        }
+        Ok(self.data)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CalibrationFile {
+    image_width: u32,
+    image_height: u32,
+    camera_name: String,
+    camera_matrix: Matrix,
+    distortion_model: String,
+    distortion_coefficients: Matrix,
+    rectification_matrix: Matrix,
+    projection_matrix: Matrix,
+}
+
+/// Turns a `camera_info_url` parameter into a path; only `file://` URLs are supported.
+pub fn url_to_path(url: &str) -> Result<PathBuf> {
+    match url.split_once("://") {
+        None => Ok(PathBuf::from(url)),
+        Some(("file", path)) => Ok(PathBuf::from(path)),
+        Some((scheme, _)) => bail!("unsupported camera_info_url scheme '{}'", scheme),
+    }
+}
+
+pub fn load(path: &Path) -> Result<CameraInfo> {
+    let yaml = fs::read_to_string(path)
+        .with_context(|| format!("failed to read calibration {}", path.display()))?;
+    let file: CalibrationFile = serde_yaml::from_str(&yaml)
+        .with_context(|| format!("failed to parse calibration {}", path.display()))?;
+
+    let mut info = CameraInfo::default();
+    info.width = file.image_width;
+    info.height = file.image_height;
+    info.distortion_model = file.distortion_model;
+    info.k = file.camera_matrix.into_data("camera_matrix")?;
+    info.d = file
+        .distortion_coefficients
+        .into_data("distortion_coefficients")?;
+    info.r = file
+        .rectification_matrix
+        .into_data("rectification_matrix")?;
+    info.p = file.projection_matrix.into_data("projection_matrix")?;
+    if info.k.len() != 9 || info.r.len() != 9 || info.p.len() != 12 {
+        bail!(
+            "calibration {} has matrices of the wrong size",
+            path.display()
+        );
+    }
+    Ok(info)
+}
+
+pub fn save(path: &Path, camera_name: &str, info: &CameraInfo) -> Result<()> {
+    let file = CalibrationFile {
+        image_width: info.width,
+        image_height: info.height,
+        camera_name: camera_name.to_string(),
+        camera_matrix: Matrix::new(3, 3, &info.k),
+        distortion_model: info.distortion_model.clone(),
+        distortion_coefficients: Matrix::new(1, info.d.len(), &info.d),
+        rectification_matrix: Matrix::new(3, 3, &info.r),
+        projection_matrix: Matrix::new(3, 4, &info.p),
+    };
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, serde_yaml::to_string(&file)?)
+        .with_context(|| format!("failed to write calibration {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn calibration() -> CameraInfo {
+        CameraInfo {
+            width: 1280,
+            height: 720,
+            distortion_model: "plumb_bob".to_string(),
+            k: vec![900.0, 0.0, 640.0, 0.0, 900.0, 360.0, 0.0, 0.0, 1.0],
+            d: vec![-0.3, 0.1, 0.001, -0.002, 0.0],
+            r: vec![1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0],
+            p: vec![
+                900.0, 0.0, 640.0, 0.0, 0.0, 900.0, 360.0, 0.0, 0.0, 0.0, 1.0, 0.0,
+            ],
+            ..CameraInfo::default()
+        }
+    }
+
+    #[test]
+    fn saved_calibration_loads_back() {
+        let path = temp_path("round_trip");
+        let info = calibration();
+        save(&path, "go2_front", &info).unwrap();
+        let loaded = load(&path);
+        let yaml = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!((loaded.width, loaded.height), (1280, 720));
+        assert_eq!(loaded.distortion_model, "plumb_bob");
+        assert_eq!(loaded.k, info.k);
+        assert_eq!(loaded.d, info.d);
+        assert_eq!(loaded.r, info.r);
+        assert_eq!(loaded.p, info.p);
+        assert!(yaml.contains("camera_name: go2_front"));
+    }
+
+    #[test]
+    fn rejects_matrix_with_wrong_value_count() {
+        let path = temp_path("short_matrix");
+        let mut info = calibration();
+        info.k.pop();
+        save(&path, "go2_front", &info).unwrap();
+        let loaded = load(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(loaded.is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_yaml() {
+        let path = temp_path("malformed");
+        fs::write(&path, "image_width: 1280\ncamera_matrix: [1, 2\n").unwrap();
+        let loaded = load(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(loaded.is_err());
+    }
+
+    #[test]
+    fn missing_file_is_an_error() {
+        assert!(load(Path::new("/nonexistent/camera_info.yaml")).is_err());
+    }
+
+    #[test]
+    fn only_file_urls_are_supported() {
+        assert_eq!(
+            url_to_path("file:///tmp/go2.yaml").unwrap(),
+            PathBuf::from("/tmp/go2.yaml")
+        );
+        assert_eq!(
+            url_to_path("/tmp/go2.yaml").unwrap(),
+            PathBuf::from("/tmp/go2.yaml")
+        );
+        assert!(url_to_path("package://go2/calibration.yaml").is_err());
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "go2_video_camera_info_{}_{}.yaml",
+            name,
+            process::id()
+        ))
+    }
+}
@@ -0,0 +1,132 @@
+//! Pre-roll buffer for event clips.
+//!
+//! Keeps the encoded video of the last few seconds so a clip can start before the
+//! event that triggered it. The buffer always starts with a keyframe, so it may
+//! hold up to one keyframe interval more than requested.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use crate::h264::AccessUnit;
+
+#[derive(Default)]
+pub struct PreRoll {
+    /// Access units with their stream time in seconds
+    units: VecDeque<(f64, Arc<AccessUnit>)>,
+}
+
+impl PreRoll {
+    pub fn new() -> PreRoll {
+        PreRoll::default()
+    }
+
+    /// Adds an access unit and drops what is older than `duration` seconds.
+    pub fn push(&mut self, time: f64, access_unit: Arc<AccessUnit>, duration: f64) {
+        if access_unit.after_loss {
+            self.clear();
+        }
+        if self.units.is_empty() && !access_unit.keyframe {
+            return;
+        }
+        self.units.push_back((time, access_unit));
+
+        // Whole keyframe intervals go at once, as long as the next one still covers the window
+        while let Some(next_keyframe) = self
+            .units
+            .iter()
+            .skip(1)
+            .position(|(_, unit)| unit.keyframe)
+            .map(|i| i + 1)
+        {
+            if self.units[next_keyframe].0 > time - duration {
+                break;
+            }
+            self.units.drain(..next_keyframe);
+        }
+    }
+
+    /// Forgets everything, after access units were lost.
+    pub fn clear(&mut self) {
+        self.units.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<AccessUnit>> {
+        self.units.iter().map(|(_, unit)| unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn unit(keyframe: bool, tag: u8) -> Arc<AccessUnit> {
+        Arc::new(AccessUnit {
+            rtp_timestamp: 0,
+            keyframe,
+            received_at: Instant::now(),
+            after_loss: false,
+            data: vec![tag],
+        })
+    }
+
+    fn tags(pre_roll: &PreRoll) -> Vec<u8> {
+        pre_roll.iter().map(|unit| unit.data[0]).collect()
+    }
+
+    /// Ten frames per second with a keyframe every `interval` frames.
+    fn fill(pre_roll: &mut PreRoll, frames: u8, interval: u8, duration: f64) {
+        for i in 0..frames {
+            pre_roll.push(f64::from(i) / 10.0, unit(i % interval == 0, i), duration);
+        }
+    }
+
+    #[test]
+    fn starts_with_a_keyframe() {
+        let mut pre_roll = PreRoll::new();
+        pre_roll.push(0.0, unit(false, 0), 2.0);
+        pre_roll.push(0.1, unit(false, 1), 2.0);
+        assert!(tags(&pre_roll).is_empty());
+        pre_roll.push(0.2, unit(true, 2), 2.0);
+        pre_roll.push(0.3, unit(false, 3), 2.0);
+        assert_eq!(tags(&pre_roll), [2, 3]);
+    }
+
+    #[test]
+    fn keeps_everything_within_the_window() {
+        let mut pre_roll = PreRoll::new();
+        fill(&mut pre_roll, 15, 5, 2.0);
+        assert_eq!(tags(&pre_roll), (0..15).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn drops_whole_keyframe_intervals() {
+        let mut pre_roll = PreRoll::new();
+        // Last frame at 2.9s, the window starts at 1.9s: the keyframe at 1.5s
+        // is the latest one that still covers it
+        fill(&mut pre_roll, 30, 5, 1.0);
+        assert_eq!(tags(&pre_roll), (15..30).collect::<Vec<_>>());
+        assert!(pre_roll.iter().next().unwrap().keyframe);
+    }
+
+    #[test]
+    fn loss_waits_for_the_next_keyframe() {
+        let mut pre_roll = PreRoll::new();
+        fill(&mut pre_roll, 7, 5, 2.0);
+        let mut after_loss = unit(false, 7);
+        Arc::get_mut(&mut after_loss).unwrap().after_loss = true;
+        pre_roll.push(0.7, after_loss, 2.0);
+        assert!(tags(&pre_roll).is_empty());
+        pre_roll.push(0.8, unit(true, 8), 2.0);
+        assert_eq!(tags(&pre_roll), [8]);
+    }
+
+    #[test]
+    fn clear_waits_for_the_next_keyframe() {
+        let mut pre_roll = PreRoll::new();
+        fill(&mut pre_roll, 7, 5, 2.0);
+        pre_roll.clear();
+        pre_roll.push(0.7, unit(false, 7), 2.0);
+        assert!(tags(&pre_roll).is_empty());
+    }
+}
@@ -0,0 +1,176 @@
+//! JPEG/PNG encoding for the `image_transport` compressed topic.
+
+use anyhow::{bail, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::{ColorType, ImageEncoder};
+use r2r::sensor_msgs::msg::{CompressedImage, Image};
+use std::borrow::Cow;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Quality 1-100
+    Jpeg(u8),
+    /// zlib level 1-9
+    Png(u8),
+}
+
+impl Format {
+    /// Parses the `compressed_format` parameter; `none` disables compression.
+    pub fn from_params(format: &str, jpeg_quality: i32, png_level: i32) -> Result<Option<Format>> {
+        match format {
+            "none" | "" => Ok(None),
+            "jpeg" => Ok(Some(Format::Jpeg(jpeg_quality.clamp(1, 100) as u8))),
+            "png" => Ok(Some(Format::Png(png_level.clamp(1, 9) as u8))),
+            _ => bail!(
+                "unknown compressed_format '{}', expected jpeg, png or none",
+                format
+            ),
+        }
+    }
+}
+
+/// Encodes an `rgb8`, `bgr8` or `mono8` image into the message `compressed_image_transport` expects.
+pub fn encode(image: &Image, format: Format) -> Result<CompressedImage> {
+    let (pixels, color) = match image.encoding.as_str() {
+        "rgb8" => (Cow::Borrowed(&image.data[..]), ColorType::Rgb8),
+        // The image crate has no BGR input, swap the channels back
+        "bgr8" => (
+            Cow::Owned(
+                image
+                    .data
+                    .chunks_exact(3)
+                    .flat_map(|p| [p[2], p[1], p[0]])
+                    .collect(),
+            ),
+            ColorType::Rgb8,
+        ),
+        "mono8" => (Cow::Borrowed(&image.data[..]), ColorType::L8),
+        other => bail!("cannot compress {} images", other),
+    };
+
+    let mut data = Vec::new();
+    let format_name = match format {
+        Format::Jpeg(quality) => {
+            JpegEncoder::new_with_quality(&mut data, quality).write_image(
+                &pixels,
+                image.width,
+                image.height,
+                color,
+            )?;
+            "jpeg"
+        }
+        Format::Png(level) => {
+            // The png encoder only has three effort settings, map zlib levels onto them
+            let compression = match level {
+                1..=3 => CompressionType::Fast,
+                4..=6 => CompressionType::Default,
+                _ => CompressionType::Best,
+            };
+            PngEncoder::new_with_quality(&mut data, compression, FilterType::Adaptive)
+                .write_image(&pixels, image.width, image.height, color)?;
+            "png"
+        }
+    };
+
+    Ok(CompressedImage {
+        header: image.header.clone(),
+        // Decoders read the original encoding before the ';'
+        format: format!(
+            "{}; {} compressed {}",
+            image.encoding,
+            format_name,
+            if color == ColorType::L8 {
+                "mono8"
+            } else {
+                "bgr8"
+            }
+        ),
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG_MAGIC: &[u8] = b"\x89PNG";
+    const JPEG_MAGIC: &[u8] = b"\xff\xd8";
+
+    /// A 4x2 image with a different value in every byte.
+    fn test_image(encoding: &str, channels: usize) -> Image {
+        let mut image = Image {
+            width: 4,
+            height: 2,
+            encoding: encoding.to_string(),
+            step: 4 * channels as u32,
+            data: (0..8 * channels).map(|i| (i * 7) as u8).collect(),
+            ..Image::default()
+        };
+        image.header.frame_id = "camera".to_string();
+        image
+    }
+
+    #[test]
+    fn encodes_every_supported_encoding() {
+        for (encoding, channels, compressed) in [
+            ("rgb8", 3, "bgr8"),
+            ("bgr8", 3, "bgr8"),
+            ("mono8", 1, "mono8"),
+        ] {
+            let image = test_image(encoding, channels);
+            for (format, name, magic) in [
+                (Format::Jpeg(90), "jpeg", JPEG_MAGIC),
+                (Format::Png(6), "png", PNG_MAGIC),
+            ] {
+                let msg = encode(&image, format).unwrap();
+                assert!(msg.data.starts_with(magic), "{} as {}", encoding, name);
+                assert_eq!(
+                    msg.format,
+                    format!("{}; {} compressed {}", encoding, name, compressed)
+                );
+                assert_eq!(msg.header.frame_id, "camera");
+            }
+        }
+    }
+
+    #[test]
+    fn bgr8_is_swapped_to_rgb() {
+        let bgr = test_image("bgr8", 3);
+        let msg = encode(&bgr, Format::Png(9)).unwrap();
+        let decoded = image::load_from_memory(&msg.data).unwrap().to_rgb8();
+        let expected: Vec<u8> = bgr
+            .data
+            .chunks_exact(3)
+            .flat_map(|p| [p[2], p[1], p[0]])
+            .collect();
+        assert_eq!(decoded.into_raw(), expected);
+    }
+
+    #[test]
+    fn rejects_yuv_encodings() {
+        for encoding in ["yuv422_yuy2", "nv12"] {
+            let image = test_image(encoding, 2);
+            assert!(encode(&image, Format::Jpeg(90)).is_err());
+            assert!(encode(&image, Format::Png(6)).is_err());
+        }
+    }
+
+    #[test]
+    fn parses_and_clamps_the_parameters() {
+        assert_eq!(Format::from_params("none", 80, 3).unwrap(), None);
+        assert_eq!(
+            Format::from_params("jpeg", 80, 3).unwrap(),
+            Some(Format::Jpeg(80))
+        );
+        assert_eq!(
+            Format::from_params("jpeg", 0, 3).unwrap(),
+            Some(Format::Jpeg(1))
+        );
+        assert_eq!(
+            Format::from_params("png", 80, 12).unwrap(),
+            Some(Format::Png(9))
+        );
+        assert!(Format::from_params("webp", 80, 3).is_err());
+    }
+}
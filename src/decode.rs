@@ -0,0 +1,226 @@
+//! Video decoding on a dedicated thread.
+//!
+//! ffmpeg blocks while it waits for packets, so the decoder gets its own thread
+//! and hands frames to the async side through a "latest frame wins" channel.
+
+use anyhow::{anyhow, Result};
+use ffmpeg::codec::{self, decoder};
+use ffmpeg::format::{self, Pixel};
+use ffmpeg::util::frame;
+use ffmpeg::{media, Packet};
+use ffmpeg_next as ffmpeg;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+use tokio::sync::watch;
+
+use crate::encoding::Encoding;
+use crate::filter::{Filter, Output};
+use crate::latest;
+use crate::pool::BufferPool;
+use crate::sdp::SdpFile;
+use crate::transform::Transform;
+
+/// A frame as it comes out of the decoder, converted to the configured encoding.
+pub struct DecodedFrame {
+    /// Counts decoded frames, gaps mean frames were dropped
+    pub sequence: u64,
+    /// When the packet that completed the frame was read from the stream
+    pub received_at: Instant,
+    pub decoded_at: Instant,
+    /// Presentation time in seconds
+    pub time: f64,
+    /// Size before cropping, rotating and scaling
+    pub source_size: (u32, u32),
+    pub width: u32,
+    pub height: u32,
+    pub encoding: Encoding,
+    /// Cropping, rotation and scaling applied to the frame
+    pub transform: Transform,
+    pub data: Vec<u8>,
+}
+
+/// Starts decoding the stream described by `sdp` on a new thread.
+///
+/// Frames are converted to the latest value of `output`. The thread ends,
+/// dropping `frames`, when the stream ends or fails.
+pub fn spawn(
+    logger: String,
+    sdp: Arc<SdpFile>,
+    options: HashMap<String, String>,
+    mut output: watch::Receiver<Output>,
+    pool: BufferPool,
+    frames: latest::Sender<DecodedFrame>,
+) -> Result<thread::JoinHandle<()>> {
+    let handle = thread::Builder::new()
+        .name("go2_video_decode".to_string())
+        .spawn(move || {
+            let current = *output.borrow_and_update();
+            let filter = Filter::new(current.encoding, &current.transform, pool.clone());
+            if let Err(e) = decode(&logger, &sdp, options, filter, output, &pool, &frames) {
+                log_warn!(&logger, "Decoder stopped: {:#}", e);
+            }
+        })?;
+    Ok(handle)
+}
+
+fn decode(
+    logger: &str,
+    sdp: &SdpFile,
+    options: HashMap<String, String>,
+    mut filter: Filter,
+    mut output: watch::Receiver<Output>,
+    pool: &BufferPool,
+    frames: &latest::Sender<DecodedFrame>,
+) -> Result<()> {
+    log_debug!(logger, "Opening local stream");
+
+    let mut input = VideoInput::open(logger, sdp.path(), &options)?;
+    log_info!(
+        logger,
+        "Input stream size: {:?}, pixel format {:?}",
+        input.size(),
+        input.format()
+    );
+
+    log_debug!(logger, "Start processing frames");
+    let mut frame = frame::Video::empty();
+    let mut sequence = 0;
+    // A failed or stalled session makes the relay end the RTP stream, so the
+    // input ends instead of blocking forever.
+    while let Some(received_at) = input.read_frame(&mut frame)? {
+        // The frame is still in ffmpeg's buffers and in the decoder's own pixel
+        // format; the filter converts it once into a pooled buffer
+        filter.follow(&mut output);
+        let (data, width, height) = filter.run(&frame)?;
+        let replaced = frames.send(DecodedFrame {
+            sequence,
+            received_at,
+            decoded_at: Instant::now(),
+            time: input.time(&frame),
+            source_size: (frame.width(), frame.height()),
+            width,
+            height,
+            encoding: filter.encoding(),
+            transform: filter.transform(),
+            data,
+        });
+        // Skipped by the publisher, its buffer can take the next frame
+        if let Some(replaced) = replaced {
+            pool.put(replaced.data);
+        }
+        sequence += 1;
+    }
+
+    Ok(())
+}
+
+/// A video file, URL or SDP description opened with ffmpeg, decoding its best
+/// video stream into frames of the decoder's native pixel format.
+pub(crate) struct VideoInput {
+    logger: String,
+    input: format::context::Input,
+    decoder: decoder::Video,
+    stream_index: usize,
+    time_base: f64,
+    /// When the last packet of the stream was read
+    received_at: Instant,
+    /// The decoder was told that no more packets follow
+    flushed: bool,
+}
+
+impl VideoInput {
+    /// Opens `path`; `options` go to both the demuxer and the decoder.
+    pub(crate) fn open(
+        logger: &str,
+        path: &Path,
+        options: &HashMap<String, String>,
+    ) -> Result<VideoInput> {
+        let input = format::input_with_dictionary(&path, dictionary(options))?;
+        let stream = input
+            .streams()
+            .best(media::Type::Video)
+            .ok_or_else(|| anyhow!("no video stream in {}", path.display()))?;
+        let stream_index = stream.index();
+        let time_base = f64::from(stream.time_base());
+
+        let context = codec::context::Context::from_parameters(stream.parameters())?;
+        let codec = decoder::find(context.id())
+            .ok_or_else(|| anyhow!("no decoder for {:?}", context.id()))?;
+        let decoder = context
+            .decoder()
+            .open_as_with(codec, dictionary(options))?
+            .video()?;
+
+        Ok(VideoInput {
+            logger: logger.to_string(),
+            input,
+            decoder,
+            stream_index,
+            time_base,
+            received_at: Instant::now(),
+            flushed: false,
+        })
+    }
+
+    /// Size of the stream as the demuxer reported it.
+    pub(crate) fn size(&self) -> (u32, u32) {
+        (self.decoder.width(), self.decoder.height())
+    }
+
+    pub(crate) fn format(&self) -> Pixel {
+        self.decoder.format()
+    }
+
+    /// Reads packets until the decoder puts out the next frame.
+    ///
+    /// Returns when the packet that completed the frame was read, or `None`
+    /// once the input ended and the decoder is drained.
+    pub(crate) fn read_frame(&mut self, frame: &mut frame::Video) -> Result<Option<Instant>> {
+        loop {
+            // One packet may complete several frames, hand those out first
+            if self.decoder.receive_frame(frame).is_ok() {
+                return Ok(Some(self.received_at));
+            }
+            if self.flushed {
+                return Ok(None);
+            }
+
+            let mut packet = Packet::empty();
+            match packet.read(&mut self.input) {
+                Ok(()) => {}
+                Err(ffmpeg::Error::Eof) => {
+                    // Frames held back for reordering come out after the end
+                    self.decoder.send_eof()?;
+                    self.flushed = true;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            }
+            if packet.stream() != self.stream_index {
+                continue;
+            }
+            self.received_at = Instant::now();
+            // Packets lost or damaged on the way are common, the decoder picks up
+            // again at the next one it can make sense of
+            if let Err(e) = self.decoder.send_packet(&packet) {
+                log_warn!(&self.logger, "Skipped a packet the decoder rejected: {}", e);
+            }
+        }
+    }
+
+    /// Presentation time of `frame` in seconds.
+    pub(crate) fn time(&self, frame: &frame::Video) -> f64 {
+        frame.timestamp().or(frame.pts()).unwrap_or_default() as f64 * self.time_base
+    }
+}
+
+fn dictionary(options: &HashMap<String, String>) -> ffmpeg::Dictionary<'static> {
+    let mut dictionary = ffmpeg::Dictionary::new();
+    for (key, value) in options {
+        dictionary.set(key, value);
+    }
+    dictionary
+}
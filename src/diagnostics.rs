@@ -0,0 +1,375 @@
+//! Health of the video pipeline as `diagnostic_msgs/DiagnosticStatus`.
+//!
+//! The pipeline stages count into a shared `Stats`; a `Reporter` turns the counters
+//! into rates over the last reporting period and grades them against `Thresholds`.
+
+use r2r::diagnostic_msgs::msg::{DiagnosticStatus, KeyValue};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ros2_go2_video::webrtc::{SessionState, Traffic};
+
+const OK: u8 = 0;
+const WARN: u8 = 1;
+const ERROR: u8 = 2;
+
+/// Counters shared by the pipeline stages, all totals since startup.
+#[derive(Default)]
+pub struct Stats {
+    pub video: Arc<Traffic>,
+    pub frames_decoded: AtomicU64,
+    pub frames_published: AtomicU64,
+    /// Decoded frames the publisher skipped because it fell behind
+    pub frames_dropped: AtomicU64,
+    /// Images the middleware failed to publish
+    pub publish_failures: AtomicU64,
+    /// Sum over published frames, in microseconds
+    pub decode_time_us: AtomicU64,
+    /// Sum over published frames of receive to publish time, in microseconds
+    pub latency_us: AtomicU64,
+    session_state: Mutex<Option<SessionState>>,
+}
+
+impl Stats {
+    pub fn set_session_state(&self, state: SessionState) {
+        *self.session_state.lock().unwrap() = Some(state);
+    }
+
+    /// Counts a published frame with its timings.
+    pub fn frame_published(&self, decode_time: Duration, latency: Duration) {
+        self.frames_published.fetch_add(1, Ordering::Relaxed);
+        self.decode_time_us
+            .fetch_add(decode_time.as_micros() as u64, Ordering::Relaxed);
+        self.latency_us
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            at: Instant::now(),
+            packets: self.video.packets.load(Ordering::Relaxed),
+            bytes: self.video.bytes.load(Ordering::Relaxed),
+            received: self.video.access_units.load(Ordering::Relaxed),
+            decoded: self.frames_decoded.load(Ordering::Relaxed),
+            published: self.frames_published.load(Ordering::Relaxed),
+            dropped: self.frames_dropped.load(Ordering::Relaxed),
+            failed: self.publish_failures.load(Ordering::Relaxed),
+            decode_time_us: self.decode_time_us.load(Ordering::Relaxed),
+            latency_us: self.latency_us.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Snapshot {
+    at: Instant,
+    packets: u64,
+    bytes: u64,
+    received: u64,
+    decoded: u64,
+    published: u64,
+    dropped: u64,
+    failed: u64,
+    decode_time_us: u64,
+    latency_us: u64,
+}
+
+/// A `(warn, error)` pair; values past either bound raise the level.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub warn: f64,
+    pub error: f64,
+}
+
+impl Limits {
+    fn above(&self, value: f64) -> u8 {
+        if value >= self.error {
+            ERROR
+        } else if value >= self.warn {
+            WARN
+        } else {
+            OK
+        }
+    }
+
+    fn below(&self, value: f64) -> u8 {
+        if value <= self.error {
+            ERROR
+        } else if value <= self.warn {
+            WARN
+        } else {
+            OK
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Thresholds {
+    /// Published frames per second, lower is worse
+    pub fps: Limits,
+    /// Seconds from receiving a frame to publishing it
+    pub latency: Limits,
+    /// Seconds spent decoding a frame
+    pub decode_time: Limits,
+    /// Share of decoded frames that were dropped
+    pub drop_ratio: Limits,
+}
+
+pub struct Reporter {
+    stats: Arc<Stats>,
+    last: Snapshot,
+}
+
+impl Reporter {
+    pub fn new(stats: Arc<Stats>) -> Reporter {
+        let last = stats.snapshot();
+        Reporter { stats, last }
+    }
+
+    /// Builds the statuses for the period since the previous report.
+    ///
+    /// `active` is false while the node is not publishing, which is not a fault.
+    pub fn report(
+        &mut self,
+        active: bool,
+        hardware_id: &str,
+        thresholds: &Thresholds,
+    ) -> Vec<DiagnosticStatus> {
+        let now = self.stats.snapshot();
+        let last = std::mem::replace(&mut self.last, now);
+        let period = now.at.duration_since(last.at).as_secs_f64().max(1e-3);
+
+        let session_state = self.stats.session_state.lock().unwrap().clone();
+        vec![
+            connection(active, session_state, &last, &now, period, hardware_id),
+            video(active, &last, &now, period, hardware_id, thresholds),
+        ]
+    }
+}
+
+/// State and traffic of the session; go2webrtc-rs does not report the ICE
+/// candidate pair, so there is no entry for it.
+fn connection(
+    active: bool,
+    state: Option<SessionState>,
+    last: &Snapshot,
+    now: &Snapshot,
+    period: f64,
+    hardware_id: &str,
+) -> DiagnosticStatus {
+    let (level, message) = match &state {
+        // Without a running session there is nothing to complain about
+        _ if !active && !matches!(state, Some(SessionState::VideoReceived { .. })) => {
+            (OK, "not active".to_string())
+        }
+        None => (WARN, "not started".to_string()),
+        Some(SessionState::Connecting) => (WARN, "connecting".to_string()),
+        Some(SessionState::MediaReceived) => {
+            (WARN, "receiving media, waiting for video".to_string())
+        }
+        Some(SessionState::VideoReceived { .. }) => (OK, "receiving video".to_string()),
+        Some(SessionState::Failed(reason)) => (ERROR, reason.clone()),
+    };
+
+    let bitrate = (now.bytes - last.bytes) as f64 * 8.0 / period;
+    DiagnosticStatus {
+        level,
+        name: "go2_video: WebRTC connection".to_string(),
+        message,
+        hardware_id: hardware_id.to_string(),
+        values: vec![
+            value(
+                "State",
+                state.map_or("none".to_string(), |s| format!("{:?}", s)),
+            ),
+            value("Packets received", now.packets),
+            value("Bitrate (kbit/s)", format!("{:.0}", bitrate / 1000.0)),
+        ],
+    }
+}
+
+fn video(
+    active: bool,
+    last: &Snapshot,
+    now: &Snapshot,
+    period: f64,
+    hardware_id: &str,
+    thresholds: &Thresholds,
+) -> DiagnosticStatus {
+    let published = now.published - last.published;
+    let decoded = now.decoded - last.decoded;
+    let dropped = now.dropped - last.dropped;
+
+    let fps = published as f64 / period;
+    let per_frame =
+        |total_us: u64| (published > 0).then(|| total_us as f64 / published as f64 / 1e6);
+    let decode_time = per_frame(now.decode_time_us - last.decode_time_us);
+    let latency = per_frame(now.latency_us - last.latency_us);
+    let drop_ratio = if decoded > 0 {
+        dropped as f64 / decoded as f64
+    } else {
+        0.0
+    };
+
+    let (level, message) = if !active {
+        (OK, "not active".to_string())
+    } else {
+        let checks = [
+            (thresholds.fps.below(fps), "low frame rate"),
+            (
+                decode_time.map_or(OK, |t| thresholds.decode_time.above(t)),
+                "slow decoding",
+            ),
+            (
+                latency.map_or(OK, |t| thresholds.latency.above(t)),
+                "high latency",
+            ),
+            (thresholds.drop_ratio.above(drop_ratio), "dropping frames"),
+        ];
+        let level = checks.iter().map(|(level, _)| *level).max().unwrap_or(OK);
+        let message = if level == OK {
+            "ok".to_string()
+        } else {
+            checks
+                .iter()
+                .filter(|(l, _)| *l == level)
+                .map(|(_, problem)| *problem)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        (level, message)
+    };
+
+    let millis = |t: Option<f64>| t.map_or("n/a".to_string(), |t| format!("{:.1}", t * 1000.0));
+    DiagnosticStatus {
+        level,
+        name: "go2_video: Video pipeline".to_string(),
+        message,
+        hardware_id: hardware_id.to_string(),
+        values: vec![
+            value("Frames received", now.received),
+            value("Frames decoded", now.decoded),
+            value("Frames published", now.published),
+            value("Frames dropped", now.dropped),
+            value("Publish failures", now.failed),
+            value("Frame rate (fps)", format!("{:.1}", fps)),
+            value("Decode time (ms)", millis(decode_time)),
+            value("Latency, receive to publish (ms)", millis(latency)),
+        ],
+    }
+}
+
+fn value(key: &str, value: impl ToString) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn thresholds() -> Thresholds {
+        let limits = |warn, error| Limits { warn, error };
+        Thresholds {
+            fps: limits(10.0, 5.0),
+            latency: limits(0.1, 0.5),
+            decode_time: limits(0.02, 0.05),
+            drop_ratio: limits(0.1, 0.5),
+        }
+    }
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            at: Instant::now(),
+            packets: 0,
+            bytes: 0,
+            received: 0,
+            decoded: 0,
+            published: 0,
+            dropped: 0,
+            failed: 0,
+            decode_time_us: 0,
+            latency_us: 0,
+        }
+    }
+
+    /// One second with `published` frames taking `decode_ms` and `latency_ms` each.
+    fn second(published: u64, dropped: u64, decode_ms: u64, latency_ms: u64) -> DiagnosticStatus {
+        let now = Snapshot {
+            decoded: published + dropped,
+            published,
+            dropped,
+            decode_time_us: published * decode_ms * 1000,
+            latency_us: published * latency_ms * 1000,
+            ..snapshot()
+        };
+        video(true, &snapshot(), &now, 1.0, "robot", &thresholds())
+    }
+
+    #[test]
+    fn limits_grade_rising_values() {
+        let limits = Limits {
+            warn: 1.0,
+            error: 2.0,
+        };
+        assert_eq!(limits.above(0.5), OK);
+        assert_eq!(limits.above(1.0), WARN);
+        assert_eq!(limits.above(1.5), WARN);
+        assert_eq!(limits.above(2.0), ERROR);
+    }
+
+    #[test]
+    fn limits_grade_falling_values() {
+        let limits = Limits {
+            warn: 10.0,
+            error: 5.0,
+        };
+        assert_eq!(limits.below(15.0), OK);
+        assert_eq!(limits.below(10.0), WARN);
+        assert_eq!(limits.below(7.0), WARN);
+        assert_eq!(limits.below(5.0), ERROR);
+    }
+
+    #[test]
+    fn healthy_video_is_ok() {
+        let status = second(30, 0, 5, 20);
+        assert_eq!(status.level, OK);
+        assert_eq!(status.message, "ok");
+    }
+
+    #[test]
+    fn message_names_the_worst_problems() {
+        // Slow decoding warns, the latency is an error
+        let status = second(30, 0, 30, 600);
+        assert_eq!(status.level, ERROR);
+        assert_eq!(status.message, "high latency");
+
+        let status = second(30, 0, 30, 200);
+        assert_eq!(status.level, WARN);
+        assert_eq!(status.message, "slow decoding, high latency");
+    }
+
+    #[test]
+    fn low_frame_rate_and_drops() {
+        let status = second(4, 6, 5, 20);
+        assert_eq!(status.level, ERROR);
+        assert_eq!(status.message, "low frame rate, dropping frames");
+    }
+
+    #[test]
+    fn no_frames_is_only_a_frame_rate_problem() {
+        let status = second(0, 0, 0, 0);
+        assert_eq!(status.level, ERROR);
+        assert_eq!(status.message, "low frame rate");
+    }
+
+    #[test]
+    fn inactive_is_not_a_fault() {
+        let status = video(false, &snapshot(), &snapshot(), 1.0, "robot", &thresholds());
+        assert_eq!(status.level, OK);
+        assert_eq!(status.message, "not active");
+    }
+}
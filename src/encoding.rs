@@ -0,0 +1,123 @@
+//! Pixel formats of the published images.
+//!
+//! The filter graph on the decoder thread converts the decoder's own frames,
+//! usually `yuv420p`, straight into these, so the publisher only hands the
+//! buffer over.
+
+use anyhow::{bail, Result};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Rgb8,
+    Bgr8,
+    Mono8,
+    /// Packed 4:2:2, Y0 U Y1 V
+    Yuv422Yuy2,
+    /// Planar 4:2:0, a Y plane followed by interleaved UV
+    Nv12,
+}
+
+impl Encoding {
+    /// Parses the `encoding` parameter, which takes the ROS encoding names.
+    pub fn from_param(encoding: &str) -> Result<Encoding> {
+        match encoding {
+            "rgb8" => Ok(Encoding::Rgb8),
+            "bgr8" => Ok(Encoding::Bgr8),
+            "mono8" => Ok(Encoding::Mono8),
+            "yuv422_yuy2" => Ok(Encoding::Yuv422Yuy2),
+            "nv12" => Ok(Encoding::Nv12),
+            _ => bail!(
+                "unknown encoding '{}', expected rgb8, bgr8, mono8, yuv422_yuy2 or nv12",
+                encoding
+            ),
+        }
+    }
+
+    /// Name for `sensor_msgs/Image.encoding`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Rgb8 => "rgb8",
+            Encoding::Bgr8 => "bgr8",
+            Encoding::Mono8 => "mono8",
+            Encoding::Yuv422Yuy2 => "yuv422_yuy2",
+            Encoding::Nv12 => "nv12",
+        }
+    }
+
+    /// Row length in bytes; for nv12 that of the Y plane.
+    pub fn step(self, width: u32) -> u32 {
+        match self {
+            Encoding::Rgb8 | Encoding::Bgr8 => width * 3,
+            Encoding::Mono8 | Encoding::Nv12 => width,
+            // Pixels come in pairs sharing U and V, an odd width ends with a whole pair
+            Encoding::Yuv422Yuy2 => width.div_ceil(2) * 4,
+        }
+    }
+
+    /// Name of the matching ffmpeg pixel format.
+    pub fn pixel_format(self) -> &'static str {
+        match self {
+            Encoding::Rgb8 => "rgb24",
+            Encoding::Bgr8 => "bgr24",
+            Encoding::Mono8 => "gray",
+            Encoding::Yuv422Yuy2 => "yuyv422",
+            Encoding::Nv12 => "nv12",
+        }
+    }
+
+    /// Planes as (bytes per row, rows), in the order they are packed into `data`.
+    pub fn planes(self, width: u32, height: u32) -> Vec<(usize, usize)> {
+        let step = self.step(width) as usize;
+        let height = height as usize;
+        match self {
+            // The UV plane has one U/V pair for every 2x2 block
+            Encoding::Nv12 => vec![(step, height), (step.div_ceil(2) * 2, height.div_ceil(2))],
+            _ => vec![(step, height)],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Encoding; 5] = [
+        Encoding::Rgb8,
+        Encoding::Bgr8,
+        Encoding::Mono8,
+        Encoding::Yuv422Yuy2,
+        Encoding::Nv12,
+    ];
+
+    #[test]
+    fn parses_its_own_names() {
+        for encoding in ALL {
+            assert_eq!(Encoding::from_param(encoding.name()).unwrap(), encoding);
+        }
+        assert!(Encoding::from_param("rgba8").is_err());
+    }
+
+    #[test]
+    fn nv12_at_odd_size() {
+        let nv12 = Encoding::Nv12;
+        assert_eq!(nv12.pixel_format(), "nv12");
+        assert_eq!(nv12.step(641), 641);
+        assert_eq!(nv12.planes(641, 361), vec![(641, 361), (642, 181)]);
+    }
+
+    #[test]
+    fn yuy2_at_odd_width() {
+        let yuy2 = Encoding::Yuv422Yuy2;
+        assert_eq!(yuy2.pixel_format(), "yuyv422");
+        assert_eq!(yuy2.step(640), 1280);
+        assert_eq!(yuy2.step(641), 1284);
+        assert_eq!(yuy2.planes(641, 361), vec![(1284, 361)]);
+    }
+
+    #[test]
+    fn packed_rgb_and_mono_rows() {
+        assert_eq!(Encoding::Rgb8.step(641), 1923);
+        assert_eq!(Encoding::Bgr8.planes(641, 3), vec![(1923, 3)]);
+        assert_eq!(Encoding::Mono8.planes(641, 3), vec![(641, 3)]);
+    }
+}
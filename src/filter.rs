@@ -0,0 +1,145 @@
+//! ffmpeg filter graph between the decoder and the publisher.
+//!
+//! Cropping, rotation, scaling and the conversion from the decoder's pixel
+//! format run in one graph on the decoder thread, so every frame is converted
+//! exactly once; frames that need none of it pass through as they are.
+
+use anyhow::{anyhow, Result};
+use ffmpeg::filter;
+use ffmpeg::format::Pixel;
+use ffmpeg::util::frame;
+use ffmpeg_next as ffmpeg;
+use tokio::sync::watch;
+
+use crate::encoding::Encoding;
+use crate::pool::BufferPool;
+use crate::transform::Transform;
+
+/// What decoded frames are turned into; it may change while decoding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Output {
+    pub encoding: Encoding,
+    pub transform: Transform,
+}
+
+pub struct Filter {
+    encoding: Encoding,
+    transform: Transform,
+    /// ffmpeg's pixel format for `encoding`
+    format: Pixel,
+    /// Filter graph description, ending with the conversion to `format`
+    spec: String,
+    /// Nothing to do for frames already in `format`
+    identity: bool,
+    /// The graph, built for frames of the given size and pixel format
+    graph: Option<(filter::Graph, (u32, u32, Pixel))>,
+    /// Reused for every frame that comes out of the graph
+    output: frame::Video,
+    pool: BufferPool,
+}
+
+impl Filter {
+    /// Output buffers come from `pool`.
+    pub fn new(encoding: Encoding, transform: &Transform, pool: BufferPool) -> Filter {
+        let mut filters = transform.filters();
+        let identity = filters.is_empty();
+        filters.push(format!("format={}", encoding.pixel_format()));
+        Filter {
+            encoding,
+            transform: *transform,
+            format: encoding
+                .pixel_format()
+                .parse()
+                .expect("ffmpeg knows every encoding's pixel format"),
+            spec: filters.join(","),
+            identity,
+            graph: None,
+            output: frame::Video::empty(),
+            pool,
+        }
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn transform(&self) -> Transform {
+        self.transform
+    }
+
+    /// Starts over with the latest output sent on `output`, if it changed.
+    pub fn follow(&mut self, output: &mut watch::Receiver<Output>) {
+        if output.has_changed().unwrap_or(false) {
+            let next = *output.borrow_and_update();
+            *self = Filter::new(next.encoding, &next.transform, self.pool.clone());
+        }
+    }
+
+    /// Runs a frame from the decoder, in whatever pixel format it has, through the graph.
+    ///
+    /// Returns the packed output in a buffer from the pool, and its width and height.
+    pub fn run(&mut self, input: &frame::Video) -> Result<(Vec<u8>, u32, u32)> {
+        if self.identity && input.format() == self.format {
+            let data = pack(input, self.encoding, &self.pool);
+            return Ok((data, input.width(), input.height()));
+        }
+
+        let key = (input.width(), input.height(), input.format());
+        if !matches!(&self.graph, Some((_, built)) if *built == key) {
+            self.graph = Some((build(&self.spec, key.0, key.1, key.2)?, key));
+        }
+        let (graph, _) = self.graph.as_mut().unwrap();
+
+        graph
+            .get("in")
+            .ok_or_else(|| anyhow!("filter graph without input"))?
+            .source()
+            .add(input)?;
+        graph
+            .get("out")
+            .ok_or_else(|| anyhow!("filter graph without output"))?
+            .sink()
+            .frame(&mut self.output)?;
+
+        let data = pack(&self.output, self.encoding, &self.pool);
+        let (width, height) = (self.output.width(), self.output.height());
+        // The sink expects a clean frame next time
+        unsafe { ffmpeg::ffi::av_frame_unref(self.output.as_mut_ptr()) };
+        Ok((data, width, height))
+    }
+}
+
+/// Copies the planes of `frame` without row padding into a buffer from `pool`.
+fn pack(frame: &frame::Video, encoding: Encoding, pool: &BufferPool) -> Vec<u8> {
+    let planes = encoding.planes(frame.width(), frame.height());
+    let mut data = pool.get(planes.iter().map(|(row, rows)| row * rows).sum());
+    for (plane, (row, rows)) in planes.into_iter().enumerate() {
+        let stride = frame.stride(plane);
+        let source = frame.data(plane);
+        for y in 0..rows {
+            data.extend_from_slice(&source[y * stride..][..row]);
+        }
+    }
+    data
+}
+
+fn build(spec: &str, width: u32, height: u32, format: Pixel) -> Result<filter::Graph> {
+    let mut graph = filter::Graph::new();
+    let buffer = filter::find("buffer").ok_or_else(|| anyhow!("ffmpeg lacks the buffer filter"))?;
+    let sink =
+        filter::find("buffersink").ok_or_else(|| anyhow!("ffmpeg lacks the buffersink filter"))?;
+    graph.add(
+        &buffer,
+        "in",
+        &format!(
+            "video_size={}x{}:pix_fmt={}:time_base=1/90000:pixel_aspect=1/1",
+            width,
+            height,
+            ffmpeg::ffi::AVPixelFormat::from(format) as i32
+        ),
+    )?;
+    graph.add(&sink, "out", "")?;
+    graph.output("in", 0)?.input("out", 0)?.parse(spec)?;
+    graph.validate()?;
+    Ok(graph)
+}
@@ -0,0 +1,737 @@
+//! H.264 depacketization (RFC 6184) of the video RTP stream.
+//!
+//! Produces Annex-B access units as they come off the wire, without decoding, for
+//! consumers that forward or store the encoded stream.
+
+use std::time::Instant;
+
+/// One encoded picture in Annex-B format.
+#[derive(Clone, Debug)]
+pub struct AccessUnit {
+    pub rtp_timestamp: u32,
+    /// Contains an IDR slice; SPS and PPS are always included in keyframes.
+    pub keyframe: bool,
+    /// When the packet completing the access unit arrived
+    pub received_at: Instant,
+    /// Access units right before this one were dropped; unless this is a keyframe
+    /// it may reference pictures that never arrived.
+    pub after_loss: bool,
+    pub data: Vec<u8>,
+}
+
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+const NAL_IDR: u8 = 5;
+const NAL_SPS: u8 = 7;
+const NAL_PPS: u8 = 8;
+const NAL_STAP_A: u8 = 24;
+const NAL_FU_A: u8 = 28;
+
+#[derive(Default)]
+pub struct Depacketizer {
+    data: Vec<u8>,
+    timestamp: Option<u32>,
+    keyframe: bool,
+    has_sps: bool,
+    has_pps: bool,
+    /// A packet of the current access unit was lost, it is dropped when complete.
+    broken: bool,
+    /// An access unit was dropped since the last one emitted
+    lost: bool,
+    in_fragment: bool,
+    last_sequence: Option<u16>,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+}
+
+impl Depacketizer {
+    pub fn new() -> Depacketizer {
+        Depacketizer::default()
+    }
+
+    /// Feeds one RTP packet, calling `emit` for every access unit it completes.
+    pub fn push(&mut self, packet: &[u8], mut emit: impl FnMut(AccessUnit)) {
+        let Some((header, payload)) = parse_rtp(packet) else {
+            return;
+        };
+
+        let gap = self
+            .last_sequence
+            .is_some_and(|last| header.sequence != last.wrapping_add(1));
+        self.last_sequence = Some(header.sequence);
+        if gap {
+            // Breaks the current access unit, unless its marker already finished it
+            self.broken = true;
+            self.in_fragment = false;
+        }
+
+        // A new timestamp starts a new picture even if the marker bit was lost
+        if self.timestamp.is_some() && self.timestamp != Some(header.timestamp) {
+            self.finish(&mut emit);
+            // The lost packets may as well have been the start of this picture
+            self.broken = gap;
+        }
+        self.timestamp = Some(header.timestamp);
+
+        self.depacketize(payload);
+
+        if header.marker {
+            self.finish(&mut emit);
+        }
+    }
+
+    fn depacketize(&mut self, payload: &[u8]) {
+        let Some(&indicator) = payload.first() else {
+            return;
+        };
+
+        match indicator & 0x1f {
+            1..=23 => self.push_nal(payload),
+            NAL_STAP_A => {
+                let mut rest = &payload[1..];
+                while rest.len() > 2 {
+                    let size = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+                    if rest.len() < 2 + size {
+                        self.broken = true;
+                        break;
+                    }
+                    self.push_nal(&rest[2..2 + size]);
+                    rest = &rest[2 + size..];
+                }
+            }
+            NAL_FU_A if payload.len() > 2 => {
+                let fu_header = payload[1];
+                let start = fu_header & 0x80 != 0;
+                let end = fu_header & 0x40 != 0;
+                let nal_type = fu_header & 0x1f;
+
+                if start {
+                    self.data.extend_from_slice(&START_CODE);
+                    self.data.push((indicator & 0xe0) | nal_type);
+                    self.note_nal(nal_type, None);
+                    self.in_fragment = true;
+                } else if !self.in_fragment {
+                    // The start of this NAL was lost
+                    self.broken = true;
+                    return;
+                }
+                self.data.extend_from_slice(&payload[2..]);
+                if end {
+                    self.in_fragment = false;
+                }
+            }
+            // STAP-B, MTAP and FU-B are not used with packetization-mode=1
+            _ => {}
+        }
+    }
+
+    fn push_nal(&mut self, nal: &[u8]) {
+        if nal.is_empty() {
+            return;
+        }
+        self.note_nal(nal[0] & 0x1f, Some(nal));
+        self.data.extend_from_slice(&START_CODE);
+        self.data.extend_from_slice(nal);
+    }
+
+    fn note_nal(&mut self, nal_type: u8, nal: Option<&[u8]>) {
+        match nal_type {
+            NAL_IDR => self.keyframe = true,
+            NAL_SPS => {
+                self.has_sps = true;
+                if let Some(nal) = nal {
+                    self.sps = Some(nal.to_vec());
+                }
+            }
+            NAL_PPS => {
+                self.has_pps = true;
+                if let Some(nal) = nal {
+                    self.pps = Some(nal.to_vec());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn finish(&mut self, emit: &mut impl FnMut(AccessUnit)) {
+        let mut data = std::mem::take(&mut self.data);
+        let keyframe = std::mem::take(&mut self.keyframe);
+        let has_parameter_sets =
+            std::mem::take(&mut self.has_sps) & std::mem::take(&mut self.has_pps);
+        let broken = std::mem::take(&mut self.broken) || self.in_fragment;
+        self.in_fragment = false;
+
+        let Some(rtp_timestamp) = self.timestamp else {
+            return;
+        };
+        if broken {
+            self.lost = true;
+            return;
+        }
+        if data.is_empty() {
+            return;
+        }
+
+        // Repeat the parameter sets in front of every IDR so each keyframe can be decoded on its own
+        if keyframe && !has_parameter_sets {
+            let (Some(sps), Some(pps)) = (&self.sps, &self.pps) else {
+                self.lost = true;
+                return;
+            };
+            let mut with_parameter_sets =
+                Vec::with_capacity(data.len() + sps.len() + pps.len() + 2 * START_CODE.len());
+            for nal in [sps, pps] {
+                with_parameter_sets.extend_from_slice(&START_CODE);
+                with_parameter_sets.extend_from_slice(nal);
+            }
+            with_parameter_sets.append(&mut data);
+            data = with_parameter_sets;
+        }
+
+        emit(AccessUnit {
+            rtp_timestamp,
+            keyframe,
+            received_at: Instant::now(),
+            after_loss: std::mem::take(&mut self.lost),
+            data,
+        });
+    }
+}
+
+struct RtpHeader {
+    marker: bool,
+    sequence: u16,
+    timestamp: u32,
+}
+
+fn parse_rtp(packet: &[u8]) -> Option<(RtpHeader, &[u8])> {
+    if packet.len() < 12 || packet[0] >> 6 != 2 || (200..=204).contains(&packet[1]) {
+        return None;
+    }
+
+    let csrc_count = (packet[0] & 0x0f) as usize;
+    let mut offset = 12 + 4 * csrc_count;
+    if packet[0] & 0x10 != 0 {
+        let extension = packet.get(offset + 2..offset + 4)?;
+        offset += 4 + 4 * u16::from_be_bytes([extension[0], extension[1]]) as usize;
+    }
+    let mut end = packet.len();
+    if packet[0] & 0x20 != 0 {
+        end = end.checked_sub(*packet.last()? as usize)?;
+    }
+
+    let header = RtpHeader {
+        marker: packet[1] & 0x80 != 0,
+        sequence: u16::from_be_bytes([packet[2], packet[3]]),
+        timestamp: u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+    };
+    Some((header, packet.get(offset..end)?))
+}
+
+/// Splits Annex-B data into NAL units, without start codes.
+pub fn nal_units(data: &[u8]) -> Vec<&[u8]> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i..i + 3] == [0, 0, 1] {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+
+    let ends = starts
+        .iter()
+        .skip(1)
+        .map(|&start| start - 3)
+        .chain([data.len()]);
+    starts
+        .iter()
+        .zip(ends)
+        .map(|(&start, end)| {
+            // Trailing zeros are the first byte of a four byte start code
+            let mut nal = &data[start..end];
+            while let [rest @ .., 0] = nal {
+                nal = rest;
+            }
+            nal
+        })
+        .filter(|nal| !nal.is_empty())
+        .collect()
+}
+
+/// Returns the SPS and PPS of a keyframe as Annex-B, the form muxers take as extradata.
+pub fn parameter_sets(access_unit: &AccessUnit) -> Option<Vec<u8>> {
+    let nals = nal_units(&access_unit.data);
+    let sps = nals.iter().find(|nal| nal[0] & 0x1f == NAL_SPS)?;
+    let pps = nals.iter().find(|nal| nal[0] & 0x1f == NAL_PPS)?;
+
+    let mut extradata = Vec::new();
+    for nal in [sps, pps] {
+        extradata.extend_from_slice(&START_CODE);
+        extradata.extend_from_slice(nal);
+    }
+    Some(extradata)
+}
+
+/// Reads the picture size from an SPS NAL unit (ITU-T H.264 7.3.2.1.1).
+pub fn sps_dimensions(sps: &[u8]) -> Option<(u32, u32)> {
+    let mut r = BitReader::new(sps.get(1..)?);
+    let profile_idc = r.bits(8)?;
+    r.bits(16)?; // constraint flags, level_idc
+    r.ue()?; // seq_parameter_set_id
+
+    let mut chroma_format_idc = 1;
+    if [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135].contains(&profile_idc) {
+        chroma_format_idc = r.ue()?;
+        if chroma_format_idc == 3 && r.bits(1)? == 1 {
+            // Colour planes coded separately behave like monochrome for cropping
+            chroma_format_idc = 0;
+        }
+        r.ue()?; // bit_depth_luma_minus8
+        r.ue()?; // bit_depth_chroma_minus8
+        r.bits(1)?; // qpprime_y_zero_transform_bypass_flag
+        if r.bits(1)? == 1 {
+            let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+            for i in 0..lists {
+                if r.bits(1)? == 1 {
+                    skip_scaling_list(&mut r, if i < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+
+    r.ue()?; // log2_max_frame_num_minus4
+    match r.ue()? {
+        0 => {
+            r.ue()?; // log2_max_pic_order_cnt_lsb_minus4
+        }
+        1 => {
+            r.bits(1)?; // delta_pic_order_always_zero_flag
+            r.se()?; // offset_for_non_ref_pic
+            r.se()?; // offset_for_top_to_bottom_field
+            for _ in 0..r.ue()? {
+                r.se()?;
+            }
+        }
+        _ => {}
+    }
+    r.ue()?; // max_num_ref_frames
+    r.bits(1)?; // gaps_in_frame_num_value_allowed_flag
+
+    let width_in_mbs = r.ue()? + 1;
+    let height_in_map_units = r.ue()? + 1;
+    let frame_mbs_only = r.bits(1)?;
+    if frame_mbs_only == 0 {
+        r.bits(1)?; // mb_adaptive_frame_field_flag
+    }
+    r.bits(1)?; // direct_8x8_inference_flag
+
+    let (mut crop_x, mut crop_y) = (0, 0);
+    if r.bits(1)? == 1 {
+        let (left, right, top, bottom) = (r.ue()?, r.ue()?, r.ue()?, r.ue()?);
+        let (sub_width, sub_height) = match chroma_format_idc {
+            0 => (1, 1),
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+        crop_x = (left + right) * sub_width;
+        crop_y = (top + bottom) * sub_height * (2 - frame_mbs_only);
+    }
+
+    let width = (width_in_mbs * 16).checked_sub(crop_x)?;
+    let height = ((2 - frame_mbs_only) * height_in_map_units * 16).checked_sub(crop_y)?;
+    Some((width, height))
+}
+
+fn skip_scaling_list(r: &mut BitReader, size: usize) -> Option<()> {
+    let mut last_scale = 8i64;
+    let mut next_scale = 8i64;
+    for _ in 0..size {
+        if next_scale != 0 {
+            next_scale = (last_scale + r.se()? + 256) % 256;
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+    Some(())
+}
+
+/// Reads RBSP bits, skipping emulation prevention bytes.
+struct BitReader<'a> {
+    data: &'a [u8],
+    byte: usize,
+    bit: u32,
+    zeros: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data,
+            byte: 0,
+            bit: 0,
+            zeros: 0,
+        }
+    }
+
+    fn bit(&mut self) -> Option<u32> {
+        if self.bit == 0 {
+            // 00 00 03 in the NAL unit stands for 00 00 in the RBSP
+            if self.zeros >= 2 && self.data.get(self.byte) == Some(&3) {
+                self.byte += 1;
+                self.zeros = 0;
+            }
+            let byte = *self.data.get(self.byte)?;
+            self.zeros = if byte == 0 { self.zeros + 1 } else { 0 };
+        }
+        let value = (self.data[self.byte] >> (7 - self.bit)) & 1;
+        self.bit += 1;
+        if self.bit == 8 {
+            self.bit = 0;
+            self.byte += 1;
+        }
+        Some(value as u32)
+    }
+
+    fn bits(&mut self, count: u32) -> Option<u32> {
+        (0..count).try_fold(0, |value, _| Some(value << 1 | self.bit()?))
+    }
+
+    /// Unsigned Exp-Golomb code.
+    fn ue(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while self.bit()? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+        Some((1u32 << leading_zeros) - 1 + self.bits(leading_zeros)?)
+    }
+
+    /// Signed Exp-Golomb code.
+    fn se(&mut self) -> Option<i64> {
+        let code = self.ue()? as i64;
+        Some(if code % 2 == 1 {
+            (code + 1) / 2
+        } else {
+            -(code / 2)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPS: [u8; 4] = [0x67, 0x42, 0xc0, 0x1f];
+    const PPS: [u8; 3] = [0x68, 0xce, 0x3c];
+    const IDR: [u8; 4] = [0x65, 0x88, 0x84, 0x00];
+    const SLICE: [u8; 3] = [0x41, 0x9a, 0x02];
+
+    fn rtp(sequence: u16, timestamp: u32, marker: bool, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x80, 96 | if marker { 0x80 } else { 0 }];
+        packet.extend_from_slice(&sequence.to_be_bytes());
+        packet.extend_from_slice(&timestamp.to_be_bytes());
+        packet.extend_from_slice(&0x1234_5678u32.to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn stap_a(nals: &[&[u8]]) -> Vec<u8> {
+        let mut payload = vec![NAL_STAP_A];
+        for nal in nals {
+            payload.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+            payload.extend_from_slice(nal);
+        }
+        payload
+    }
+
+    /// Splits `nal` into FU-A payloads of at most `size` bytes of NAL data.
+    fn fu_a(nal: &[u8], size: usize) -> Vec<Vec<u8>> {
+        let chunks: Vec<_> = nal[1..].chunks(size).collect();
+        chunks
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut header = nal[0] & 0x1f;
+                if i == 0 {
+                    header |= 0x80;
+                }
+                if i == chunks.len() - 1 {
+                    header |= 0x40;
+                }
+                let mut payload = vec![(nal[0] & 0xe0) | NAL_FU_A, header];
+                payload.extend_from_slice(chunk);
+                payload
+            })
+            .collect()
+    }
+
+    fn annex_b(nals: &[&[u8]]) -> Vec<u8> {
+        nals.iter()
+            .flat_map(|nal| START_CODE.iter().chain(nal.iter()))
+            .copied()
+            .collect()
+    }
+
+    fn push_all(depacketizer: &mut Depacketizer, packets: &[Vec<u8>]) -> Vec<AccessUnit> {
+        let mut units = Vec::new();
+        for packet in packets {
+            depacketizer.push(packet, |unit| units.push(unit));
+        }
+        units
+    }
+
+    #[test]
+    fn parses_rtp_header() {
+        let packet = rtp(7, 3000, true, &SLICE);
+        let (header, payload) = parse_rtp(&packet).unwrap();
+        assert!(header.marker);
+        assert_eq!(header.sequence, 7);
+        assert_eq!(header.timestamp, 3000);
+        assert_eq!(payload, SLICE);
+    }
+
+    #[test]
+    fn parses_rtp_csrcs_extension_and_padding() {
+        let mut packet = rtp(1, 0, false, &[]);
+        packet[0] |= 0x10 | 0x20 | 1;
+        packet.extend_from_slice(&[0; 4]); // one CSRC
+        packet.extend_from_slice(&[0xbe, 0xde, 0, 1, 1, 2, 3, 4]); // one word of extension
+        packet.extend_from_slice(&SLICE);
+        packet.extend_from_slice(&[0, 0, 3]); // padding, its length last
+        let (_, payload) = parse_rtp(&packet).unwrap();
+        assert_eq!(payload, SLICE);
+    }
+
+    #[test]
+    fn rejects_rtcp_and_short_packets() {
+        let mut rtcp = rtp(1, 0, false, &[]);
+        rtcp[1] = 200;
+        assert!(parse_rtp(&rtcp).is_none());
+        assert!(parse_rtp(&[0x80, 96, 0, 1]).is_none());
+    }
+
+    #[test]
+    fn single_nal_packets() {
+        let mut depacketizer = Depacketizer::new();
+        let units = push_all(
+            &mut depacketizer,
+            &[rtp(1, 3000, false, &SLICE), rtp(2, 3000, true, &SLICE)],
+        );
+        assert_eq!(units.len(), 1);
+        assert_eq!(units[0].rtp_timestamp, 3000);
+        assert!(!units[0].keyframe);
+        assert_eq!(units[0].data, annex_b(&[&SLICE, &SLICE]));
+    }
+
+    #[test]
+    fn stap_a_keyframe() {
+        let mut depacketizer = Depacketizer::new();
+        let units = push_all(
+            &mut depacketizer,
+            &[
+                rtp(1, 0, false, &stap_a(&[&SPS, &PPS])),
+                rtp(2, 0, true, &IDR),
+            ],
+        );
+        assert_eq!(units.len(), 1);
+        assert!(units[0].keyframe);
+        assert_eq!(units[0].data, annex_b(&[&SPS, &PPS, &IDR]));
+    }
+
+    #[test]
+    fn fu_a_reassembly() {
+        let nal: Vec<u8> = [0x65].into_iter().chain(1..=100).collect();
+        let mut packets = vec![rtp(1, 0, false, &stap_a(&[&SPS, &PPS]))];
+        for (i, payload) in fu_a(&nal, 30).iter().enumerate() {
+            packets.push(rtp(2 + i as u16, 0, i == 3, payload));
+        }
+        let mut depacketizer = Depacketizer::new();
+        let units = push_all(&mut depacketizer, &packets);
+        assert_eq!(units.len(), 1);
+        assert!(units[0].keyframe);
+        assert_eq!(units[0].data, annex_b(&[&SPS, &PPS, &nal]));
+    }
+
+    #[test]
+    fn gap_drops_the_access_unit() {
+        let nal: Vec<u8> = [0x41].into_iter().chain(1..=100).collect();
+        let fragments = fu_a(&nal, 30);
+        let mut depacketizer = Depacketizer::new();
+        let units = push_all(
+            &mut depacketizer,
+            &[
+                rtp(1, 0, false, &fragments[0]),
+                // Sequence 2 is lost
+                rtp(3, 0, false, &fragments[2]),
+                rtp(4, 0, true, &fragments[3]),
+                rtp(5, 3000, true, &SLICE),
+            ],
+        );
+        assert_eq!(units.len(), 1);
+        assert_eq!(units[0].rtp_timestamp, 3000);
+        assert_eq!(units[0].data, annex_b(&[&SLICE]));
+        assert!(units[0].after_loss);
+    }
+
+    #[test]
+    fn lost_start_of_picture_drops_it() {
+        let mut depacketizer = Depacketizer::new();
+        let units = push_all(
+            &mut depacketizer,
+            &[
+                rtp(1, 0, true, &SLICE),
+                // Sequence 2, the first packet at 3000, is lost
+                rtp(3, 3000, false, &SLICE),
+                rtp(4, 3000, true, &SLICE),
+                rtp(5, 6000, true, &SLICE),
+            ],
+        );
+        assert_eq!(
+            units.iter().map(|u| u.rtp_timestamp).collect::<Vec<_>>(),
+            [0, 6000]
+        );
+        assert!(!units[0].after_loss);
+        assert!(units[1].after_loss);
+    }
+
+    #[test]
+    fn lost_marker_packet_drops_both_pictures() {
+        let mut depacketizer = Depacketizer::new();
+        let units = push_all(
+            &mut depacketizer,
+            &[
+                rtp(1, 0, false, &SLICE),
+                // Sequence 2 ended either picture 0 or started picture 3000
+                rtp(3, 3000, true, &SLICE),
+                rtp(4, 6000, true, &SLICE),
+            ],
+        );
+        assert_eq!(
+            units.iter().map(|u| u.rtp_timestamp).collect::<Vec<_>>(),
+            [6000]
+        );
+        assert!(units[0].after_loss);
+    }
+
+    #[test]
+    fn new_timestamp_finishes_without_marker() {
+        let mut depacketizer = Depacketizer::new();
+        let units = push_all(
+            &mut depacketizer,
+            &[rtp(1, 0, false, &SLICE), rtp(2, 3000, true, &SLICE)],
+        );
+        assert_eq!(
+            units.iter().map(|u| u.rtp_timestamp).collect::<Vec<_>>(),
+            [0, 3000]
+        );
+    }
+
+    #[test]
+    fn repeats_parameter_sets_before_idr() {
+        let mut depacketizer = Depacketizer::new();
+        let units = push_all(
+            &mut depacketizer,
+            &[
+                rtp(1, 0, false, &stap_a(&[&SPS, &PPS])),
+                rtp(2, 0, true, &IDR),
+                rtp(3, 3000, true, &IDR),
+            ],
+        );
+        assert_eq!(units.len(), 2);
+        assert_eq!(units[1].data, annex_b(&[&SPS, &PPS, &IDR]));
+        assert_eq!(parameter_sets(&units[1]), Some(annex_b(&[&SPS, &PPS])));
+    }
+
+    #[test]
+    fn drops_idr_before_parameter_sets() {
+        let mut depacketizer = Depacketizer::new();
+        let units = push_all(&mut depacketizer, &[rtp(1, 0, true, &IDR)]);
+        assert!(units.is_empty());
+    }
+
+    #[test]
+    fn splits_nal_units() {
+        let data = [0, 0, 0, 1, 0x67, 1, 0, 0, 1, 0x68, 2, 0, 0, 0, 1, 0x65];
+        assert_eq!(
+            nal_units(&data),
+            [&[0x67, 1][..], &[0x68, 2][..], &[0x65][..]]
+        );
+    }
+
+    /// Writes SPS fields MSB first.
+    #[derive(Default)]
+    struct BitWriter {
+        bits: Vec<u8>,
+    }
+
+    impl BitWriter {
+        fn bits(&mut self, value: u32, count: u32) -> &mut Self {
+            for i in (0..count).rev() {
+                self.bits.push((value >> i) as u8 & 1);
+            }
+            self
+        }
+
+        fn ue(&mut self, value: u32) -> &mut Self {
+            let code = value + 1;
+            let length = 32 - code.leading_zeros();
+            self.bits(0, length - 1).bits(code, length)
+        }
+
+        fn finish(&mut self) -> Vec<u8> {
+            // rbsp_stop_one_bit and alignment
+            self.bits.push(1);
+            while self.bits.len() % 8 != 0 {
+                self.bits.push(0);
+            }
+            self.bits
+                .chunks(8)
+                .map(|byte| byte.iter().fold(0, |value, bit| value << 1 | bit))
+                .collect()
+        }
+    }
+
+    fn sps(profile_idc: u32, width_mbs: u32, height_mbs: u32, crop_bottom: u32) -> Vec<u8> {
+        let mut w = BitWriter::default();
+        w.bits(profile_idc, 8).bits(0, 8).bits(40, 8).ue(0);
+        if profile_idc == 100 {
+            // chroma_format_idc 4:2:0, 8 bit, no scaling matrices
+            w.ue(1).ue(0).ue(0).bits(0, 1).bits(0, 1);
+        }
+        w.ue(0).ue(0).ue(0); // frame_num, pic_order_cnt_type 0 and its lsb
+        w.ue(1).bits(0, 1); // max_num_ref_frames, gaps
+        w.ue(width_mbs - 1).ue(height_mbs - 1);
+        w.bits(1, 1).bits(1, 1); // frame_mbs_only, direct_8x8_inference
+        if crop_bottom > 0 {
+            w.bits(1, 1).ue(0).ue(0).ue(0).ue(crop_bottom);
+        } else {
+            w.bits(0, 1);
+        }
+        w.bits(0, 1); // vui_parameters_present_flag
+        [0x67].into_iter().chain(w.finish()).collect()
+    }
+
+    #[test]
+    fn sps_dimensions_baseline() {
+        assert_eq!(sps_dimensions(&sps(66, 80, 45, 0)), Some((1280, 720)));
+    }
+
+    #[test]
+    fn sps_dimensions_high_with_cropping() {
+        // 1088 coded rows, 4 chroma rows cropped
+        assert_eq!(sps_dimensions(&sps(100, 120, 68, 4)), Some((1920, 1080)));
+    }
+
+    #[test]
+    fn sps_dimensions_truncated() {
+        let sps = sps(66, 80, 45, 0);
+        assert_eq!(sps_dimensions(&sps[..4]), None);
+    }
+}
@@ -0,0 +1,136 @@
+//! Single-slot "latest value wins" channel.
+//!
+//! The sender never blocks: a value that was not picked up before the next one
+//! arrives is replaced and counted as dropped.
+
+use futures::future;
+use futures::task::AtomicWaker;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+struct State<T> {
+    value: Option<T>,
+    closed: bool,
+    dropped: u64,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    waker: AtomicWaker,
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            value: None,
+            closed: false,
+            dropped: 0,
+        }),
+        waker: AtomicWaker::new(),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+impl<T> Sender<T> {
+    /// Stores `value`, replacing the previous one if it has not been received yet.
+    ///
+    /// Returns the replaced value, so the sender can reuse what it holds.
+    pub fn send(&self, value: T) -> Option<T> {
+        let replaced = {
+            let mut state = self.shared.state.lock().unwrap();
+            let replaced = state.value.replace(value);
+            if replaced.is_some() {
+                state.dropped += 1;
+            }
+            replaced
+        };
+        self.shared.waker.wake();
+        replaced
+    }
+
+    /// Returns true once the receiver is gone and nothing will pick up new values.
+    pub fn is_closed(&self) -> bool {
+        Arc::strong_count(&self.shared) == 1
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.waker.wake();
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Waits for the next value; returns `None` once the sender is gone.
+    pub async fn recv(&mut self) -> Option<T> {
+        future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Polling version of [`Receiver::recv`].
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        // Registered before looking, so a value sent in between still wakes us
+        self.shared.waker.register(cx.waker());
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(value) = state.value.take() {
+            return Poll::Ready(Some(value));
+        }
+        if state.closed {
+            return Poll::Ready(None);
+        }
+        Poll::Pending
+    }
+
+    /// Number of values replaced before they were received.
+    pub fn dropped(&self) -> u64 {
+        self.shared.state.lock().unwrap().dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn latest_value_wins() {
+        let (sender, mut receiver) = channel();
+        assert_eq!(sender.send(1), None);
+        assert_eq!(sender.send(2), Some(1));
+        assert_eq!(receiver.dropped(), 1);
+        assert_eq!(block_on(receiver.recv()), Some(2));
+        assert_eq!(sender.send(3), None);
+        assert_eq!(receiver.dropped(), 1);
+    }
+
+    #[test]
+    fn closes_when_the_sender_drops() {
+        let (sender, mut receiver) = channel();
+        sender.send(1);
+        drop(sender);
+        // What was sent last is still delivered
+        assert_eq!(block_on(receiver.recv()), Some(1));
+        assert_eq!(block_on(receiver.recv()), None);
+    }
+
+    #[test]
+    fn sender_notices_the_receiver_drop() {
+        let (sender, receiver) = channel::<u32>();
+        assert!(!sender.is_closed());
+        drop(receiver);
+        assert!(sender.is_closed());
+    }
+}
@@ -0,0 +1,31 @@
+//! Video and audio from a Unitree Go2 over WebRTC.
+//!
+//! [`Go2VideoSource`] connects to the robot and yields decoded frames as a
+//! [`futures::Stream`]; the `ros2_go2_video` binary publishes them on ROS topics.
+//!
+//! ROS support is behind the default `ros` feature. Without it the library
+//! builds with only ffmpeg installed and logs through `tracing`.
+
+#[macro_use]
+mod log;
+
+pub mod audio;
+pub mod clip;
+pub mod decode;
+pub mod encoding;
+pub mod filter;
+pub mod h264;
+pub mod latest;
+pub mod options;
+pub mod pool;
+pub mod record;
+pub mod replay;
+pub mod sdp;
+pub mod source;
+pub mod stamp;
+pub mod transform;
+pub mod watchdog;
+pub mod webrtc;
+
+pub use decode::DecodedFrame;
+pub use source::{Frames, Go2Video, Go2VideoSource};
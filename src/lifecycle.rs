@@ -0,0 +1,423 @@
+//! Managed (lifecycle) node support.
+//!
+//! r2r has no lifecycle node, so the services of the ROS 2 lifecycle state machine
+//! are implemented here on top of a plain node. Transitions are checked against the
+//! current state and handed to the node as `TransitionRequest`s; the node reports
+//! whether the transition succeeded and the state machine moves on accordingly.
+
+use futures::{Stream, StreamExt};
+use r2r::lifecycle_msgs::msg::{
+    State as StateMsg, Transition as TransitionMsg, TransitionDescription, TransitionEvent,
+};
+use r2r::lifecycle_msgs::srv::{
+    ChangeState, GetAvailableStates, GetAvailableTransitions, GetState,
+};
+use r2r::{Publisher, QosProfile, ServiceRequest};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task;
+
+/// Primary states, with the ids of `lifecycle_msgs/State`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    Unconfigured = 1,
+    Inactive = 2,
+    Active = 3,
+    Finalized = 4,
+}
+
+impl State {
+    fn label(self) -> &'static str {
+        match self {
+            State::Unconfigured => "unconfigured",
+            State::Inactive => "inactive",
+            State::Active => "active",
+            State::Finalized => "finalized",
+        }
+    }
+
+    fn msg(self) -> StateMsg {
+        StateMsg {
+            id: self as u8,
+            label: self.label().to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transition {
+    Configure,
+    Cleanup,
+    Activate,
+    Deactivate,
+    Shutdown,
+}
+
+impl Transition {
+    const ALL: [Transition; 5] = [
+        Transition::Configure,
+        Transition::Cleanup,
+        Transition::Activate,
+        Transition::Deactivate,
+        Transition::Shutdown,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            Transition::Configure => "configure",
+            Transition::Cleanup => "cleanup",
+            Transition::Activate => "activate",
+            Transition::Deactivate => "deactivate",
+            Transition::Shutdown => "shutdown",
+        }
+    }
+
+    /// Transition id as in `lifecycle_msgs/Transition`; shutdown has one per start state.
+    fn id(self, from: State) -> u8 {
+        match (self, from) {
+            (Transition::Configure, _) => 1,
+            (Transition::Cleanup, _) => 2,
+            (Transition::Activate, _) => 3,
+            (Transition::Deactivate, _) => 4,
+            (Transition::Shutdown, State::Unconfigured) => 5,
+            (Transition::Shutdown, State::Inactive) => 6,
+            (Transition::Shutdown, _) => 7,
+        }
+    }
+
+    /// The transition state the node is in while the transition runs.
+    fn transition_state(self) -> StateMsg {
+        let (id, label) = match self {
+            Transition::Configure => (10, "configuring"),
+            Transition::Cleanup => (11, "cleaningup"),
+            Transition::Shutdown => (12, "shuttingdown"),
+            Transition::Activate => (13, "activating"),
+            Transition::Deactivate => (14, "deactivating"),
+        };
+        StateMsg {
+            id,
+            label: label.to_string(),
+        }
+    }
+
+    /// Returns the goal state if the transition is allowed from `from`.
+    fn goal(self, from: State) -> Option<State> {
+        match (self, from) {
+            (Transition::Configure, State::Unconfigured) => Some(State::Inactive),
+            (Transition::Cleanup, State::Inactive) => Some(State::Unconfigured),
+            (Transition::Activate, State::Inactive) => Some(State::Active),
+            (Transition::Deactivate, State::Active) => Some(State::Inactive),
+            (Transition::Shutdown, State::Finalized) => None,
+            (Transition::Shutdown, _) => Some(State::Finalized),
+            _ => None,
+        }
+    }
+
+    fn from_msg(msg: &TransitionMsg, from: State) -> Option<Transition> {
+        Transition::ALL.into_iter().find(|t| {
+            if msg.id == 0 {
+                t.label() == msg.label
+            } else {
+                t.id(from) == msg.id
+            }
+        })
+    }
+}
+
+/// A transition the node has to carry out; answer with `finish`.
+pub struct TransitionRequest {
+    pub transition: Transition,
+    done: oneshot::Sender<bool>,
+}
+
+impl TransitionRequest {
+    pub fn finish(self, success: bool) {
+        let _ = self.done.send(success);
+    }
+}
+
+struct Inner {
+    logger: String,
+    state: Mutex<State>,
+    requests: mpsc::Sender<TransitionRequest>,
+    events: Publisher<TransitionEvent>,
+}
+
+#[derive(Clone)]
+pub struct Lifecycle {
+    inner: Arc<Inner>,
+}
+
+impl Lifecycle {
+    /// Creates the lifecycle services on `node`.
+    ///
+    /// Transitions requested through them arrive on the returned receiver.
+    pub fn serve(
+        node: &mut r2r::Node,
+    ) -> r2r::Result<(Lifecycle, mpsc::Receiver<TransitionRequest>)> {
+        let (requests, transitions) = mpsc::channel(1);
+        let lifecycle = Lifecycle {
+            inner: Arc::new(Inner {
+                logger: node.logger().to_string(),
+                state: Mutex::new(State::Unconfigured),
+                requests,
+                events: node.create_publisher::<TransitionEvent>(
+                    "~/transition_event",
+                    QosProfile::default(),
+                )?,
+            }),
+        };
+
+        let change_state =
+            node.create_service::<ChangeState::Service>("~/change_state", QosProfile::default())?;
+        let get_state =
+            node.create_service::<GetState::Service>("~/get_state", QosProfile::default())?;
+        let get_available_states = node.create_service::<GetAvailableStates::Service>(
+            "~/get_available_states",
+            QosProfile::default(),
+        )?;
+        let get_available_transitions = node.create_service::<GetAvailableTransitions::Service>(
+            "~/get_available_transitions",
+            QosProfile::default(),
+        )?;
+
+        task::spawn(lifecycle.clone().serve_change_state(change_state));
+        task::spawn(lifecycle.clone().serve_get_state(get_state));
+        task::spawn(serve_get_available_states(get_available_states));
+        task::spawn(
+            lifecycle
+                .clone()
+                .serve_get_available_transitions(get_available_transitions),
+        );
+
+        Ok((lifecycle, transitions))
+    }
+
+    /// Runs `transition` if the current state allows it; returns whether it succeeded.
+    pub async fn trigger(&self, transition: Transition) -> bool {
+        // Holding the state lock for the whole transition serializes concurrent requests
+        let mut state = self.inner.state.lock().await;
+        let start = *state;
+        let Some(goal) = transition.goal(start) else {
+            r2r::log_warn!(
+                &self.inner.logger,
+                "Transition {} is not allowed in state {}",
+                transition.label(),
+                start.label()
+            );
+            return false;
+        };
+
+        let transition_msg = TransitionMsg {
+            id: transition.id(start),
+            label: transition.label().to_string(),
+        };
+        self.publish_event(&transition_msg, start.msg(), transition.transition_state());
+
+        let (done, result) = oneshot::channel();
+        let request = TransitionRequest { transition, done };
+        let success =
+            self.inner.requests.send(request).await.is_ok() && result.await.unwrap_or(false);
+
+        // A failed transition returns to where it started, except shutdown which always finishes
+        let end = if success || transition == Transition::Shutdown {
+            goal
+        } else {
+            start
+        };
+        self.publish_event(&transition_msg, transition.transition_state(), end.msg());
+        *state = end;
+
+        r2r::log_info!(
+            &self.inner.logger,
+            "Transition {} {}, now {}",
+            transition.label(),
+            if success { "succeeded" } else { "failed" },
+            end.label()
+        );
+        success
+    }
+
+    fn publish_event(&self, transition: &TransitionMsg, start: StateMsg, goal: StateMsg) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_nanos() as u64)
+            .unwrap_or(0);
+        let _ = self.inner.events.publish(&TransitionEvent {
+            timestamp,
+            transition: transition.clone(),
+            start_state: start,
+            goal_state: goal,
+        });
+    }
+
+    async fn serve_change_state(
+        self,
+        mut requests: impl Stream<Item = ServiceRequest<ChangeState::Service>> + Unpin,
+    ) {
+        while let Some(request) = requests.next().await {
+            let start = *self.inner.state.lock().await;
+            let success = match Transition::from_msg(&request.message.transition, start) {
+                Some(transition) => self.trigger(transition).await,
+                None => {
+                    r2r::log_warn!(
+                        &self.inner.logger,
+                        "Unknown transition {} '{}'",
+                        request.message.transition.id,
+                        request.message.transition.label
+                    );
+                    false
+                }
+            };
+            let _ = request.respond(ChangeState::Response { success });
+        }
+    }
+
+    async fn serve_get_state(
+        self,
+        mut requests: impl Stream<Item = ServiceRequest<GetState::Service>> + Unpin,
+    ) {
+        while let Some(request) = requests.next().await {
+            let state = *self.inner.state.lock().await;
+            let _ = request.respond(GetState::Response {
+                current_state: state.msg(),
+            });
+        }
+    }
+
+    async fn serve_get_available_transitions(
+        self,
+        mut requests: impl Stream<Item = ServiceRequest<GetAvailableTransitions::Service>> + Unpin,
+    ) {
+        while let Some(request) = requests.next().await {
+            let start = *self.inner.state.lock().await;
+            let available_transitions = Transition::ALL
+                .into_iter()
+                .filter_map(|transition| {
+                    let goal = transition.goal(start)?;
+                    Some(TransitionDescription {
+                        transition: TransitionMsg {
+                            id: transition.id(start),
+                            label: transition.label().to_string(),
+                        },
+                        start_state: start.msg(),
+                        goal_state: goal.msg(),
+                    })
+                })
+                .collect();
+            let _ = request.respond(GetAvailableTransitions::Response {
+                available_transitions,
+            });
+        }
+    }
+}
+
+async fn serve_get_available_states(
+    mut requests: impl Stream<Item = ServiceRequest<GetAvailableStates::Service>> + Unpin,
+) {
+    while let Some(request) = requests.next().await {
+        let available_states = [
+            State::Unconfigured,
+            State::Inactive,
+            State::Active,
+            State::Finalized,
+        ]
+        .into_iter()
+        .map(State::msg)
+        .collect();
+        let _ = request.respond(GetAvailableStates::Response { available_states });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATES: [State; 4] = [
+        State::Unconfigured,
+        State::Inactive,
+        State::Active,
+        State::Finalized,
+    ];
+
+    #[test]
+    fn goals_follow_the_state_machine() {
+        use State::*;
+        use Transition::*;
+        let allowed = [
+            (Configure, Unconfigured, Inactive),
+            (Cleanup, Inactive, Unconfigured),
+            (Activate, Inactive, Active),
+            (Deactivate, Active, Inactive),
+            (Shutdown, Unconfigured, Finalized),
+            (Shutdown, Inactive, Finalized),
+            (Shutdown, Active, Finalized),
+        ];
+        for transition in Transition::ALL {
+            for from in STATES {
+                let expected = allowed
+                    .iter()
+                    .find(|(t, f, _)| *t == transition && *f == from)
+                    .map(|(_, _, goal)| *goal);
+                assert_eq!(
+                    transition.goal(from),
+                    expected,
+                    "{:?} from {:?}",
+                    transition,
+                    from
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_transitions_have_no_goal() {
+        assert_eq!(Transition::Activate.goal(State::Unconfigured), None);
+        assert_eq!(Transition::Configure.goal(State::Active), None);
+        assert_eq!(Transition::Cleanup.goal(State::Active), None);
+        assert_eq!(Transition::Deactivate.goal(State::Inactive), None);
+        assert_eq!(Transition::Shutdown.goal(State::Finalized), None);
+    }
+
+    #[test]
+    fn shutdown_id_depends_on_the_start_state() {
+        assert_eq!(Transition::Shutdown.id(State::Unconfigured), 5);
+        assert_eq!(Transition::Shutdown.id(State::Inactive), 6);
+        assert_eq!(Transition::Shutdown.id(State::Active), 7);
+    }
+
+    #[test]
+    fn requests_match_by_id_or_label() {
+        let msg = |id: u8, label: &str| TransitionMsg {
+            id,
+            label: label.to_string(),
+        };
+        assert_eq!(
+            Transition::from_msg(&msg(3, ""), State::Inactive),
+            Some(Transition::Activate)
+        );
+        assert_eq!(
+            Transition::from_msg(&msg(0, "cleanup"), State::Inactive),
+            Some(Transition::Cleanup)
+        );
+        assert_eq!(
+            Transition::from_msg(&msg(6, ""), State::Inactive),
+            Some(Transition::Shutdown)
+        );
+        // The shutdown id of another start state
+        assert_eq!(Transition::from_msg(&msg(7, ""), State::Inactive), None);
+        assert_eq!(
+            Transition::from_msg(&msg(0, "restart"), State::Inactive),
+            None
+        );
+        assert_eq!(Transition::from_msg(&msg(42, ""), State::Inactive), None);
+    }
+
+    #[test]
+    fn state_ids_match_lifecycle_msgs() {
+        let ids: Vec<_> = STATES.into_iter().map(|state| state.msg().id).collect();
+        assert_eq!(ids, [1, 2, 3, 4]);
+        assert_eq!(State::Active.msg().label, "active");
+    }
+}
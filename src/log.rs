@@ -0,0 +1,44 @@
+//! Logging for the library code.
+//!
+//! With the `ros` feature messages go to the ROS logger named by the caller,
+//! without it to `tracing`, with the logger name as a field.
+
+#[cfg(feature = "ros")]
+macro_rules! log_debug {
+    ($logger:expr, $($arg:tt)+) => { r2r::log_debug!($logger, $($arg)+) };
+}
+
+#[cfg(feature = "ros")]
+macro_rules! log_info {
+    ($logger:expr, $($arg:tt)+) => { r2r::log_info!($logger, $($arg)+) };
+}
+
+#[cfg(feature = "ros")]
+macro_rules! log_warn {
+    ($logger:expr, $($arg:tt)+) => { r2r::log_warn!($logger, $($arg)+) };
+}
+
+#[cfg(feature = "ros")]
+macro_rules! log_error {
+    ($logger:expr, $($arg:tt)+) => { r2r::log_error!($logger, $($arg)+) };
+}
+
+#[cfg(not(feature = "ros"))]
+macro_rules! log_debug {
+    ($logger:expr, $($arg:tt)+) => { tracing::debug!(logger = %$logger, $($arg)+) };
+}
+
+#[cfg(not(feature = "ros"))]
+macro_rules! log_info {
+    ($logger:expr, $($arg:tt)+) => { tracing::info!(logger = %$logger, $($arg)+) };
+}
+
+#[cfg(not(feature = "ros"))]
+macro_rules! log_warn {
+    ($logger:expr, $($arg:tt)+) => { tracing::warn!(logger = %$logger, $($arg)+) };
+}
+
+#[cfg(not(feature = "ros"))]
+macro_rules! log_error {
+    ($logger:expr, $($arg:tt)+) => { tracing::error!(logger = %$logger, $($arg)+) };
+}
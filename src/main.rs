@@ -1,146 +1,2236 @@
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use dotenv::dotenv;
+use futures::{Stream, StreamExt};
 use std::collections::HashMap;
 use std::env;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use tokio::task;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, oneshot, watch};
+use tokio::task::{self, JoinHandle};
 use tokio::time;
-use video_rs::{self, Decoder, Locator};
 
 use r2r;
-use r2r::sensor_msgs::msg::Image;
-use r2r::{QosProfile, RosParams};
+use r2r::audio_common_msgs::msg::{AudioData, AudioDataStamped, AudioInfo};
+use r2r::diagnostic_msgs::msg::DiagnosticArray;
+use r2r::foxglove_msgs::msg::CompressedVideo;
+use r2r::go2_video_interfaces::srv::Snapshot;
+use r2r::sensor_msgs::msg::{CameraInfo, CompressedImage, Image};
+use r2r::sensor_msgs::srv::SetCameraInfo;
+use r2r::std_msgs::msg::{Empty, UInt64};
+use r2r::std_srvs::srv::Trigger;
+use r2r::{ParameterValue, Publisher, QosProfile, RosParams, ServiceRequest};
+
+use lifecycle::{Lifecycle, Transition};
+
+use ros2_go2_video::{
+    audio, clip, encoding, filter, h264, latest, options, pool, record, replay, sdp, stamp,
+    transform, watchdog, webrtc, Frames, Go2VideoSource,
+};
+
+mod camera_info;
+mod compressed;
+mod diagnostics;
+mod lifecycle;
+mod snapshot;
 
 #[derive(RosParams, Default, Debug)]
 struct NodeParams {
+    /// Where the video comes from: webrtc (the robot) or file (replay of source_url)
+    source: String,
+    /// Video file (MP4, MKV, raw H.264) or ffmpeg URL replayed when source is file
+    source_url: String,
+    /// Start the replay over when it reaches the end
+    replay_loop: bool,
+    /// Replay speed, 1.0 is real time
+    replay_rate: f64,
     robot_ip: String,
     robot_token: String,
     video_port: u16,
     audio_port: u16,
     debug_webrtc: bool,
+    /// H.264 profile-level-id announced to the decoder
+    h264_profile_level_id: String,
+    /// ffmpeg options as key=value, merged over the defaults and decoder_options_file
+    decoder_options: Vec<String>,
+    /// YAML mapping of ffmpeg options merged over the defaults, empty for none
+    decoder_options_file: String,
+    /// Seconds to wait for video from the robot before giving up
+    connect_timeout: f64,
+    /// Seconds without video after which the session is restarted
+    stall_timeout: f64,
+    /// Seconds without a new decoded frame (none at all, or only repeats) before warning
+    watchdog_warn_timeout: f64,
+    /// Seconds without a new decoded frame before the decoder is restarted to resync on the
+    /// next keyframe the robot sends; no keyframe is requested, go2webrtc-rs cannot send
+    /// RTCP to the robot, so with a long keyframe interval this step waits for it
+    watchdog_resync_timeout: f64,
+    /// Seconds without a new decoded frame before the session is restarted
+    watchdog_restart_timeout: f64,
+    /// Initial delay in seconds between reconnect attempts
+    reconnect_delay_min: f64,
+    /// Upper bound in seconds for the exponential reconnect backoff
+    reconnect_delay_max: f64,
+    /// Camera name stored in the calibration file
+    camera_name: String,
+    /// Calibration file (camera_info_manager YAML), defaults to ~/.ros/camera_info/<camera_name>.yaml
+    camera_info_url: String,
+    /// Format of the image/compressed topic: jpeg, png or none
+    compressed_format: String,
+    /// JPEG quality, 1-100
+    jpeg_quality: i32,
+    /// PNG compression level, 1-9
+    png_level: i32,
+    /// Published video: raw (decoded images), h264 (encoded passthrough) or both
+    video_output: String,
+    /// Pixel format of the published images: rgb8, bgr8, mono8, yuv422_yuy2 or nv12
+    encoding: String,
+    /// Left edge of the crop rectangle in source pixels
+    crop_x: i32,
+    /// Top edge of the crop rectangle in source pixels
+    crop_y: i32,
+    /// Width of the crop rectangle, 0 disables cropping
+    crop_width: i32,
+    /// Height of the crop rectangle, 0 disables cropping
+    crop_height: i32,
+    /// Clockwise rotation after cropping: 0, 90, 180 or 270 degrees
+    rotation: i32,
+    /// Mirroring after rotation: none, horizontal or vertical
+    flip: String,
+    /// Width of the published images, 0 keeps the width (or follows the aspect ratio)
+    output_width: i32,
+    /// Height of the published images, 0 keeps the height (or follows the aspect ratio)
+    output_height: i32,
+    /// Scale into output_width x output_height without distorting the picture
+    output_keep_aspect: bool,
+    /// Image stamps: receive (ROS time the frame's last packet arrived) or stream (stream timestamps aligned to ROS time)
+    stamp_source: String,
+    /// How fast stream stamps follow a growing delay (clock drift), 0-1 per frame
+    stamp_drift_gain: f64,
+    /// Decode the robot's audio track and publish it as PCM
+    publish_audio: bool,
+    /// Image topic, relative to the node namespace; camera_info is published next to it
+    image_topic: String,
+    /// Audio topic, relative to the node namespace
+    audio_topic: String,
+    /// frame_id of the published messages
+    frame_id: String,
+    /// QoS preset: default (reliable, depth 10) or sensor_data (best effort, depth 5)
+    qos_preset: String,
+    /// Overrides the preset reliability: reliable or best_effort, empty keeps the preset
+    qos_reliability: String,
+    /// Overrides the preset durability: volatile or transient_local, empty keeps the preset
+    qos_durability: String,
+    /// Overrides the preset history depth, 0 keeps the preset
+    qos_depth: i32,
+    /// Configure and activate on startup; disable when a lifecycle manager drives the node
+    autostart: bool,
+    /// Where the snapshot service saves images (.jpg or .png), {stamp} expands to the image stamp; empty only returns them.
+    /// A path in the request is used for that snapshot instead
+    snapshot_path: String,
+    /// Make snapshots wait for a frame decoded after the next keyframe instead of returning the latest one
+    snapshot_wait_fresh: bool,
+    /// Seconds a snapshot waits for a frame
+    snapshot_timeout: f64,
+    /// Directory for recordings, defaults to ~/go2_video
+    record_directory: String,
+    /// Recording file name without extension; {datetime} (UTC), {stamp} and {segment} are expanded
+    record_filename: String,
+    /// Recording container: mkv or mp4 (fragmented)
+    record_format: String,
+    /// Seconds after which recording continues in a new file at the next keyframe, 0 for no limit
+    record_segment_duration: f64,
+    /// Megabytes after which recording continues in a new file at the next keyframe, 0 for no limit
+    record_segment_size: f64,
+    /// Buffer the pre-roll so event clips can be saved; takes effect when the session is set up again
+    clip_enabled: bool,
+    /// Seconds of video before the trigger that go into an event clip
+    clip_pre_roll: f64,
+    /// Seconds of video after the trigger that go into an event clip
+    clip_post_roll: f64,
+    /// Directory for event clips, defaults to the recording directory
+    clip_directory: String,
+    /// Event clip file name without extension; {datetime} (UTC) and {stamp} are expanded
+    clip_filename: String,
+    /// Seconds between /diagnostics messages
+    diagnostics_period: f64,
+    /// Published frame rate below which diagnostics warn
+    diagnostics_fps_warn: f64,
+    /// Published frame rate below which diagnostics report an error
+    diagnostics_fps_error: f64,
+    /// Seconds from receive to publish above which diagnostics warn
+    diagnostics_latency_warn: f64,
+    /// Seconds from receive to publish above which diagnostics report an error
+    diagnostics_latency_error: f64,
+    /// Seconds of decoding per frame above which diagnostics warn
+    diagnostics_decode_time_warn: f64,
+    /// Seconds of decoding per frame above which diagnostics report an error
+    diagnostics_decode_time_error: f64,
+    /// Share of dropped frames, 0-1, above which diagnostics warn
+    diagnostics_drop_ratio_warn: f64,
+    /// Share of dropped frames, 0-1, above which diagnostics report an error
+    diagnostics_drop_ratio_error: f64,
 }
 
-fn get_decoder_options() -> video_rs::Options {
-    let mut options: HashMap<String, String> = HashMap::new();
-    options.insert(
-        "protocol_whitelist".to_string(),
-        "file,rtp,udp,https,tls,tcp".to_string(),
-    );
-    options.insert("flags".to_string(), "low_delay".to_string());
-    options.insert("analyzeduration".to_string(), "4M".to_string());
-    options.insert("probesize".to_string(), "4M".to_string());
-    options.insert("vf".to_string(), "setpts=0".to_string());
+/// Settings that stay fixed for the lifetime of a session.
+struct SessionSettings {
+    source: Go2VideoSource,
+    video_output: VideoOutput,
+    clip_enabled: bool,
+    watchdog: watchdog::Thresholds,
+}
+
+/// Parameters that take effect when the WebRTC session is set up again.
+///
+/// Everything not listed here or in `STARTUP_PARAMS` is read again for every
+/// frame or reconnect attempt.
+const SESSION_PARAMS: &[&str] = &[
+    "source",
+    "source_url",
+    "replay_loop",
+    "replay_rate",
+    "robot_ip",
+    "robot_token",
+    "video_port",
+    "audio_port",
+    "debug_webrtc",
+    "h264_profile_level_id",
+    "decoder_options",
+    "decoder_options_file",
+    "stall_timeout",
+    "watchdog_warn_timeout",
+    "watchdog_resync_timeout",
+    "watchdog_restart_timeout",
+    "video_output",
+    "clip_enabled",
+];
+
+/// Parameters that are only read at startup.
+const STARTUP_PARAMS: &[&str] = &[
+    "camera_name",
+    "camera_info_url",
+    "image_topic",
+    "audio_topic",
+    "qos_preset",
+    "qos_reliability",
+    "qos_durability",
+    "qos_depth",
+    "autostart",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum VideoOutput {
+    Raw,
+    H264,
+    Both,
+}
+
+#[derive(Clone, Debug)]
+enum Source {
+    WebRtc,
+    Replay(replay::Settings),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StampSource {
+    Receive,
+    Stream,
+}
+
+/// Stamps frames, following changes to `stamp_source` and `stamp_drift_gain`.
+struct Stamping {
+    logger: String,
+    source: StampSource,
+    clock: stamp::StreamClock,
+    params_changed: watch::Receiver<()>,
+}
+
+impl Stamping {
+    fn new(logger: &str, outputs: &Outputs, node_params: &Mutex<NodeParams>) -> Stamping {
+        let mut stamping = Stamping {
+            logger: logger.to_string(),
+            source: StampSource::Receive,
+            clock: stamp::StreamClock::new(0.0),
+            params_changed: outputs.params_changed.clone(),
+        };
+        stamping.params_changed.borrow_and_update();
+        stamping.read(node_params);
+        stamping
+    }
+
+    fn read(&mut self, node_params: &Mutex<NodeParams>) {
+        let np = node_params.lock().unwrap();
+        match np.stamp_source() {
+            Ok(source) => self.source = source,
+            Err(e) => r2r::log_error!(&self.logger, "{:#}", e),
+        }
+        self.clock.set_drift_gain(np.stamp_drift_gain);
+    }
+
+    /// Returns the stamp for a frame received at `now`, with its stream time if known.
+    fn stamp(
+        &mut self,
+        node_params: &Mutex<NodeParams>,
+        stream_time: Option<f64>,
+        now: Duration,
+    ) -> Duration {
+        if self.params_changed.has_changed().unwrap_or(false) {
+            self.params_changed.borrow_and_update();
+            self.read(node_params);
+        }
+        match (self.source, stream_time) {
+            (StampSource::Stream, Some(time)) => self.clock.stamp(time, now),
+            _ => now,
+        }
+    }
+}
+
+impl VideoOutput {
+    fn raw(self) -> bool {
+        self != VideoOutput::H264
+    }
 
-    return video_rs::Options::from(options);
+    fn h264(self) -> bool {
+        self != VideoOutput::Raw
+    }
+}
+
+impl NodeParams {
+    fn video_source(&self) -> Result<Go2VideoSource> {
+        Ok(Go2VideoSource::new(&self.robot_ip)
+            .robot_token(&self.robot_token)
+            .video_port(self.video_port)
+            .audio_port(self.audio_port)
+            .debug_webrtc(self.debug_webrtc)
+            .stall_timeout(seconds("stall_timeout", self.stall_timeout)?)
+            .connect_timeout(self.connect_timeout()?)
+            .h264_profile_level_id(&self.h264_profile_level_id)
+            .decoder_options(self.decoder_options()?)
+            .encoding(encoding::Encoding::from_param(&self.encoding)?)
+            .transform(self.transform()?))
+    }
+
+    fn session_settings(&self) -> Result<SessionSettings> {
+        Ok(SessionSettings {
+            source: self.video_source()?,
+            video_output: self.video_output()?,
+            clip_enabled: self.clip_enabled,
+            watchdog: self.watchdog()?,
+        })
+    }
+
+    /// What decoded frames are converted to, switched without reconnecting.
+    fn output(&self) -> Result<filter::Output> {
+        Ok(filter::Output {
+            encoding: encoding::Encoding::from_param(&self.encoding)?,
+            transform: self.transform()?,
+        })
+    }
+
+    fn watchdog(&self) -> Result<watchdog::Thresholds> {
+        let thresholds = watchdog::Thresholds {
+            warn: seconds("watchdog_warn_timeout", self.watchdog_warn_timeout)?,
+            resync: seconds("watchdog_resync_timeout", self.watchdog_resync_timeout)?,
+            restart: seconds("watchdog_restart_timeout", self.watchdog_restart_timeout)?,
+        };
+        if !(thresholds.warn <= thresholds.resync && thresholds.resync <= thresholds.restart) {
+            bail!("watchdog timeouts must be in order: warn <= resync <= restart");
+        }
+        Ok(thresholds)
+    }
+
+    /// Checks everything a session needs, so a bad value fails the configure transition.
+    fn validate(&self) -> Result<()> {
+        self.source()?;
+        self.session_settings()?;
+        self.stamp_source()?;
+        options::validate(&self.decoder_options()?)?;
+        let encoding = self.output()?.encoding;
+        if self.compressed_format()?.is_some()
+            && matches!(
+                encoding,
+                encoding::Encoding::Yuv422Yuy2 | encoding::Encoding::Nv12
+            )
+        {
+            bail!(
+                "compressed_format {} needs encoding rgb8, bgr8 or mono8, not {}",
+                self.compressed_format,
+                encoding.name()
+            );
+        }
+        self.reconnect_delay(1)?;
+        seconds("diagnostics_period", self.diagnostics_period)?;
+        seconds("snapshot_timeout", self.snapshot_timeout)?;
+        self.record_settings()?;
+        self.clip_settings()?;
+        let profile = &self.h264_profile_level_id;
+        if profile.len() != 6 || !profile.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!(
+                "h264_profile_level_id must be 6 hex digits, got '{}'",
+                profile
+            );
+        }
+        Ok(())
+    }
+
+    fn qos(&self) -> Result<QosProfile> {
+        let mut qos = match self.qos_preset.as_str() {
+            "default" => QosProfile::default(),
+            "sensor_data" => QosProfile::sensor_data(),
+            other => bail!(
+                "unknown qos_preset '{}', expected default or sensor_data",
+                other
+            ),
+        };
+        qos = match self.qos_reliability.as_str() {
+            "" => qos,
+            "reliable" => qos.reliable(),
+            "best_effort" => qos.best_effort(),
+            other => bail!(
+                "unknown qos_reliability '{}', expected reliable or best_effort",
+                other
+            ),
+        };
+        qos = match self.qos_durability.as_str() {
+            "" => qos,
+            "volatile" => qos.volatile(),
+            "transient_local" => qos.transient_local(),
+            other => bail!(
+                "unknown qos_durability '{}', expected volatile or transient_local",
+                other
+            ),
+        };
+        if self.qos_depth > 0 {
+            qos = qos.keep_last(self.qos_depth as usize);
+        }
+        Ok(qos)
+    }
+
+    fn diagnostic_thresholds(&self) -> diagnostics::Thresholds {
+        let limits = |warn, error| diagnostics::Limits { warn, error };
+        diagnostics::Thresholds {
+            fps: limits(self.diagnostics_fps_warn, self.diagnostics_fps_error),
+            latency: limits(
+                self.diagnostics_latency_warn,
+                self.diagnostics_latency_error,
+            ),
+            decode_time: limits(
+                self.diagnostics_decode_time_warn,
+                self.diagnostics_decode_time_error,
+            ),
+            drop_ratio: limits(
+                self.diagnostics_drop_ratio_warn,
+                self.diagnostics_drop_ratio_error,
+            ),
+        }
+    }
+
+    fn record_settings(&self) -> Result<record::Settings> {
+        let directory = if self.record_directory.is_empty() {
+            let home = env::var("HOME").map_err(|_| anyhow!("record_directory is not set"))?;
+            PathBuf::from(home).join("go2_video")
+        } else {
+            PathBuf::from(&self.record_directory)
+        };
+        let limit = |value: f64| (value > 0.0).then_some(value);
+        Ok(record::Settings {
+            directory,
+            filename: self.record_filename.clone(),
+            container: record::Container::from_param(&self.record_format)?,
+            segment_duration: limit(self.record_segment_duration).map(Duration::from_secs_f64),
+            segment_size: limit(self.record_segment_size).map(|mb| (mb * 1e6) as u64),
+        })
+    }
+
+    fn clip_settings(&self) -> Result<record::Settings> {
+        let directory = if self.clip_directory.is_empty() {
+            self.record_settings()?.directory
+        } else {
+            PathBuf::from(&self.clip_directory)
+        };
+        Ok(record::Settings {
+            directory,
+            filename: self.clip_filename.clone(),
+            container: record::Container::Mp4,
+            segment_duration: None,
+            segment_size: None,
+        })
+    }
+
+    /// Options from `decoder_options_file` and `decoder_options`, the parameter wins.
+    fn decoder_option_overrides(&self) -> Result<HashMap<String, String>> {
+        let mut overrides = if self.decoder_options_file.is_empty() {
+            HashMap::new()
+        } else {
+            options::load(Path::new(&self.decoder_options_file))?
+        };
+        overrides.extend(options::parse(&self.decoder_options)?);
+        Ok(overrides)
+    }
+
+    /// Options for the local RTP streams: the defaults with the overrides on top.
+    fn decoder_options(&self) -> Result<HashMap<String, String>> {
+        let mut options = options::defaults();
+        options.extend(self.decoder_option_overrides()?);
+        Ok(options)
+    }
+
+    fn camera_info_path(&self) -> Result<PathBuf> {
+        if !self.camera_info_url.is_empty() {
+            return camera_info::url_to_path(&self.camera_info_url);
+        }
+        let home = env::var("HOME").map_err(|_| anyhow!("camera_info_url is not set"))?;
+        Ok(PathBuf::from(home)
+            .join(".ros/camera_info")
+            .join(format!("{}.yaml", self.camera_name)))
+    }
+
+    fn compressed_format(&self) -> Result<Option<compressed::Format>> {
+        compressed::Format::from_params(&self.compressed_format, self.jpeg_quality, self.png_level)
+    }
+
+    fn video_output(&self) -> Result<VideoOutput> {
+        match self.video_output.as_str() {
+            "raw" => Ok(VideoOutput::Raw),
+            "h264" => Ok(VideoOutput::H264),
+            "both" => Ok(VideoOutput::Both),
+            other => bail!(
+                "unknown video_output '{}', expected raw, h264 or both",
+                other
+            ),
+        }
+    }
+
+    fn source(&self) -> Result<Source> {
+        match self.source.as_str() {
+            "webrtc" => Ok(Source::WebRtc),
+            "file" => {
+                if self.source_url.is_empty() {
+                    bail!("source_url is required when source is file");
+                }
+                if !(self.replay_rate > 0.0) {
+                    bail!("replay_rate must be positive, got {}", self.replay_rate);
+                }
+                Ok(Source::Replay(replay::Settings {
+                    url: self.source_url.clone(),
+                    looping: self.replay_loop,
+                    rate: self.replay_rate,
+                }))
+            }
+            other => bail!("unknown source '{}', expected webrtc or file", other),
+        }
+    }
+
+    fn transform(&self) -> Result<transform::Transform> {
+        let pixels = |name: &str, value: i32| {
+            u32::try_from(value)
+                .map_err(|_| anyhow!("{} must not be negative, got {}", name, value))
+        };
+        let crop = transform::Crop {
+            x: pixels("crop_x", self.crop_x)?,
+            y: pixels("crop_y", self.crop_y)?,
+            width: pixels("crop_width", self.crop_width)?,
+            height: pixels("crop_height", self.crop_height)?,
+        };
+        Ok(transform::Transform {
+            crop: (crop.width > 0 && crop.height > 0).then_some(crop),
+            rotation: transform::Rotation::from_param(self.rotation)?,
+            flip: transform::Flip::from_param(&self.flip)?,
+            width: pixels("output_width", self.output_width)?,
+            height: pixels("output_height", self.output_height)?,
+            keep_aspect: self.output_keep_aspect,
+        })
+    }
+
+    fn stamp_source(&self) -> Result<StampSource> {
+        match self.stamp_source.as_str() {
+            "receive" => Ok(StampSource::Receive),
+            "stream" => Ok(StampSource::Stream),
+            other => bail!(
+                "unknown stamp_source '{}', expected receive or stream",
+                other
+            ),
+        }
+    }
+
+    fn connect_timeout(&self) -> Result<Duration> {
+        seconds("connect_timeout", self.connect_timeout)
+    }
+
+    /// Delay before reconnect attempt number `failures` (counted from 1).
+    fn reconnect_delay(&self, failures: u32) -> Result<Duration> {
+        let min = seconds("reconnect_delay_min", self.reconnect_delay_min)?;
+        let max = seconds("reconnect_delay_max", self.reconnect_delay_max)?;
+        let factor = 2f64.powi(failures.saturating_sub(1).min(30) as i32);
+        Ok(min.mul_f64(factor).min(max))
+    }
+}
+
+/// Returns `name` in the same namespace as `topic`.
+fn sibling_topic(topic: &str, name: &str) -> String {
+    match topic.rsplit_once('/') {
+        Some((parent, _)) => format!("{}/{}", parent, name),
+        None => name.to_string(),
+    }
+}
+
+fn seconds(name: &str, value: f64) -> Result<Duration> {
+    if !(value > 0.0) {
+        bail!("{} must be positive, got {}", name, value);
+    }
+    Ok(Duration::from_secs_f64(value))
+}
+
+async fn log_session_state(
+    logger: String,
+    mut state: watch::Receiver<webrtc::SessionState>,
+    stats: Arc<diagnostics::Stats>,
+) {
+    loop {
+        let current = state.borrow_and_update().clone();
+        stats.set_session_state(current.clone());
+        match current {
+            webrtc::SessionState::Connecting => {
+                r2r::log_info!(&logger, "WebRTC: connecting to the robot")
+            }
+            webrtc::SessionState::MediaReceived => {
+                r2r::log_info!(&logger, "WebRTC: media is flowing")
+            }
+            webrtc::SessionState::VideoReceived { payload_type } => {
+                r2r::log_info!(
+                    &logger,
+                    "WebRTC: receiving video (payload type {})",
+                    payload_type
+                )
+            }
+            webrtc::SessionState::Failed(reason) => {
+                r2r::log_error!(&logger, "WebRTC: {}", reason)
+            }
+        }
+        if state.changed().await.is_err() {
+            break;
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Load environment variables from .env file
     dotenv().ok();
-    // Initialize the logger for video_rs
+    // Initialize the logger
     tracing_subscriber::fmt::init();
 
     // Create a new r2r context and ros2 node
     let ctx = r2r::Context::create()?;
     let mut node = r2r::Node::create(ctx, "go2_video", "")?;
-    let mut clock = r2r::Clock::create(r2r::ClockType::RosTime)?;
     // let nl = node.logger();
     r2r::log_info!(node.logger(), "Starting {}", node.name()?);
 
-    // Initialize the video_rs library
-    video_rs::init().unwrap();
+    ffmpeg_next::init()?;
 
     // create our parameters and set default values
     let node_params = Arc::new(Mutex::new({
         let mut p = NodeParams::default();
+        p.source = "webrtc".to_string();
+        p.source_url = "".to_string();
+        p.replay_loop = true;
+        p.replay_rate = 1.0;
         p.robot_ip = env::var("ROBOT_IP").unwrap_or_else(|_| "".to_string());
         p.robot_token = env::var("ROBOT_TOKEN").unwrap_or_else(|_| "".to_string());
         p.video_port = 4002;
         p.audio_port = 4000;
         p.debug_webrtc = true;
+        p.h264_profile_level_id = "42e01f".to_string();
+        p.decoder_options = Vec::new();
+        p.decoder_options_file = "".to_string();
+        p.connect_timeout = 10.0;
+        p.stall_timeout = 5.0;
+        p.watchdog_warn_timeout = 2.0;
+        p.watchdog_resync_timeout = 3.0;
+        p.watchdog_restart_timeout = 10.0;
+        p.reconnect_delay_min = 1.0;
+        p.reconnect_delay_max = 30.0;
+        p.camera_name = "go2_front_camera".to_string();
+        p.camera_info_url = "".to_string();
+        p.compressed_format = "none".to_string();
+        p.jpeg_quality = 80;
+        p.png_level = 6;
+        p.video_output = "raw".to_string();
+        p.encoding = "rgb8".to_string();
+        p.crop_x = 0;
+        p.crop_y = 0;
+        p.crop_width = 0;
+        p.crop_height = 0;
+        p.rotation = 0;
+        p.flip = "none".to_string();
+        p.output_width = 0;
+        p.output_height = 0;
+        p.output_keep_aspect = true;
+        p.stamp_source = "receive".to_string();
+        p.stamp_drift_gain = 0.01;
+        p.publish_audio = false;
+        p.image_topic = "go2_camera/color/image".to_string();
+        p.audio_topic = "go2_camera/audio".to_string();
+        p.frame_id = "front_camera".to_string();
+        p.qos_preset = "default".to_string();
+        p.qos_reliability = "".to_string();
+        p.qos_durability = "".to_string();
+        p.qos_depth = 0;
+        p.autostart = true;
+        p.snapshot_path = "".to_string();
+        p.snapshot_wait_fresh = false;
+        p.snapshot_timeout = 5.0;
+        p.record_directory = "".to_string();
+        p.record_filename = "go2_{datetime}_{segment}".to_string();
+        p.record_format = "mkv".to_string();
+        p.record_segment_duration = 300.0;
+        p.record_segment_size = 0.0;
+        p.clip_enabled = true;
+        p.clip_pre_roll = 10.0;
+        p.clip_post_roll = 5.0;
+        p.clip_directory = "".to_string();
+        p.clip_filename = "go2_clip_{datetime}".to_string();
+        p.diagnostics_period = 1.0;
+        p.diagnostics_fps_warn = 10.0;
+        p.diagnostics_fps_error = 1.0;
+        p.diagnostics_latency_warn = 0.2;
+        p.diagnostics_latency_error = 1.0;
+        p.diagnostics_decode_time_warn = 0.05;
+        p.diagnostics_decode_time_error = 0.2;
+        p.diagnostics_drop_ratio_warn = 0.1;
+        p.diagnostics_drop_ratio_error = 0.5;
         p
     }));
-    let _ = node.make_derived_parameter_handler(node_params.clone())?;
+    let (parameter_handler, parameter_events) =
+        node.make_derived_parameter_handler(node_params.clone())?;
+    task::spawn(parameter_handler);
+
+    let logger = node.logger().to_string();
+    // Counts parameter changes that need a new session
+    let reconnect = Arc::new(watch::channel(0u64).0);
+    let (params_changed_tx, params_changed) = watch::channel(());
+    task::spawn(handle_parameter_events(
+        logger.clone(),
+        parameter_events,
+        reconnect.clone(),
+        params_changed_tx,
+    ));
+
+    // Load the calibration, without one the camera_info only carries the image size
+    let (calibration_path, camera_name) = {
+        let np = node_params.lock().unwrap();
+        (np.camera_info_path()?, np.camera_name.clone())
+    };
+    let calibration = if calibration_path.exists() {
+        r2r::log_info!(
+            &logger,
+            "Loading calibration from {}",
+            calibration_path.display()
+        );
+        camera_info::load(&calibration_path)?
+    } else {
+        r2r::log_warn!(
+            &logger,
+            "Calibration {} not found, camera is uncalibrated",
+            calibration_path.display()
+        );
+        CameraInfo::default()
+    };
+
+    // Topic names and QoS are only read at startup
+    let (image_topic, audio_topic, qos) = {
+        let np = node_params.lock().unwrap();
+        r2r::log_info!(
+            &logger,
+            "Publishing images on {} (qos preset {})",
+            np.image_topic,
+            np.qos_preset
+        );
+        (np.image_topic.clone(), np.audio_topic.clone(), np.qos()?)
+    };
+
+    // Create the publishers for the image and camera_info topics
+    let (latest_tx, latest_rx) = watch::channel(None);
+    let outputs = Arc::new(Outputs {
+        image: node.create_publisher::<Image>(&image_topic, qos.clone())?,
+        camera_info: node.create_publisher::<CameraInfo>(
+            &sibling_topic(&image_topic, "camera_info"),
+            qos.clone(),
+        )?,
+        calibration: Arc::new(Mutex::new(calibration)),
+        latest: latest_tx,
+        pool: pool::BufferPool::new(),
+        keyframe_at: watch::channel(None).0,
+        recording: watch::channel(false).0,
+        params_changed,
+        clip_requests: Mutex::new(Vec::new()),
+        h264: node
+            .create_publisher::<CompressedVideo>(&format!("{}/h264", image_topic), qos.clone())?,
+        audio: node.create_publisher::<AudioData>(&audio_topic, qos.clone())?,
+        audio_stamped: node.create_publisher::<AudioDataStamped>(
+            &format!("{}_stamped", audio_topic),
+            qos.clone(),
+        )?,
+        // Latched, late subscribers still need to know how to interpret the samples
+        audio_info: node.create_publisher::<AudioInfo>(
+            &format!("{}_info", audio_topic),
+            QosProfile::default().transient_local().keep_last(1),
+        )?,
+        dropped_frames: node
+            .create_publisher::<UInt64>("~/dropped_frames", QosProfile::default())?,
+        stats: Arc::new(diagnostics::Stats::default()),
+        active: AtomicBool::new(false),
+        webrtc: AtomicBool::new(false),
+    });
 
-    r2r::log_info!(node.logger(), "Connecting to the robot's video stream");
-    let _webrtc_handle = task::spawn({
-        let node_params_clone = node_params.clone();
+    let compressed_publisher = node
+        .create_publisher::<CompressedImage>(&format!("{}/compressed", image_topic), qos.clone())?;
+    task::spawn(publish_compressed(
+        logger.clone(),
+        latest_rx,
+        compressed_publisher,
+        node_params.clone(),
+    ));
 
+    let set_camera_info = node.create_service::<SetCameraInfo::Service>(
+        &sibling_topic(&image_topic, "set_camera_info"),
+        QosProfile::default(),
+    )?;
+    task::spawn(serve_set_camera_info(
+        logger.clone(),
+        set_camera_info,
+        outputs.calibration.clone(),
+        calibration_path,
+        camera_name,
+    ));
+
+    let snapshot = node.create_service::<Snapshot::Service>("~/snapshot", QosProfile::default())?;
+    // Latched, so a script can call the service and then pick up the image
+    let snapshot_image = node.create_publisher::<Image>(
+        "~/snapshot/image",
+        QosProfile::default().transient_local().keep_last(1),
+    )?;
+    let start_recording =
+        node.create_service::<Trigger::Service>("~/start_recording", QosProfile::default())?;
+    let stop_recording =
+        node.create_service::<Trigger::Service>("~/stop_recording", QosProfile::default())?;
+    task::spawn(serve_recording_switch(
+        start_recording,
+        true,
+        outputs.clone(),
+        node_params.clone(),
+    ));
+    task::spawn(serve_recording_switch(
+        stop_recording,
+        false,
+        outputs.clone(),
+        node_params.clone(),
+    ));
+
+    let save_clip =
+        node.create_service::<Trigger::Service>("~/save_clip", QosProfile::default())?;
+    let clip_trigger = node.subscribe::<Empty>("~/clip_trigger", QosProfile::default())?;
+    task::spawn(serve_save_clip(
+        logger.clone(),
+        save_clip,
+        outputs.clone(),
+        node_params.clone(),
+    ));
+    task::spawn(handle_clip_triggers(
+        logger.clone(),
+        clip_trigger,
+        outputs.clone(),
+        node_params.clone(),
+    ));
+
+    task::spawn(serve_snapshot(
+        logger.clone(),
+        snapshot,
+        snapshot_image,
+        outputs.clone(),
+        node_params.clone(),
+    ));
+
+    let diagnostics_publisher =
+        node.create_publisher::<DiagnosticArray>("/diagnostics", QosProfile::default())?;
+    task::spawn({
+        let logger = logger.clone();
+        let diagnostics =
+            publish_diagnostics(diagnostics_publisher, outputs.clone(), node_params.clone());
         async move {
-            let (video_port, audio_port, robot_ip, robot_token, debug_webrtc) = {
-                let np = node_params_clone.lock().unwrap(); // Lock is only held within this block
-                (
-                    np.video_port,
-                    np.audio_port,
-                    np.robot_ip.clone(),
-                    np.robot_token.clone(),
-                    np.debug_webrtc,
-                )
+            if let Err(e) = diagnostics.await {
+                r2r::log_error!(&logger, "Stopped publishing diagnostics: {:#}", e);
+            }
+        }
+    });
+
+    let (lifecycle, mut transitions) = Lifecycle::serve(&mut node)?;
+    let autostart = node_params.lock().unwrap().autostart;
+
+    // Spin the node so parameter changes and services are processed, until shutdown
+    let spinning = Arc::new(AtomicBool::new(true));
+    let spinner = task::spawn_blocking({
+        let spinning = spinning.clone();
+        move || {
+            while spinning.load(Ordering::Relaxed) {
+                node.spin_once(Duration::from_millis(100));
+            }
+        }
+    });
+
+    if autostart {
+        // Goes through the state machine so get_state and transition_event stay accurate
+        let lifecycle = lifecycle.clone();
+        task::spawn(async move {
+            let _ = lifecycle.trigger(Transition::Configure).await
+                && lifecycle.trigger(Transition::Activate).await;
+        });
+    } else {
+        r2r::log_info!(&logger, "Waiting for the configure transition");
+    }
+
+    let mut video = VideoNode {
+        logger,
+        node_params,
+        outputs,
+        reconnect,
+        lifecycle: lifecycle.clone(),
+        configured: None,
+        supervisor: None,
+    };
+    loop {
+        let request = tokio::select! {
+            request = transitions.recv() => match request {
+                Some(request) => request,
+                None => break,
+            },
+            // Ctrl-C shuts down through the state machine like a shutdown request
+            _ = tokio::signal::ctrl_c() => {
+                let lifecycle = lifecycle.clone();
+                task::spawn(async move { lifecycle.trigger(Transition::Shutdown).await });
+                continue;
+            }
+        };
+        let transition = request.transition;
+        let result = video.transition(transition);
+        if let Err(e) = &result {
+            r2r::log_error!(&video.logger, "{:?} failed: {:#}", transition, e);
+        }
+        request.finish(result.is_ok());
+        if transition == Transition::Shutdown {
+            break;
+        }
+    }
+
+    // The runtime waits for blocking tasks when it shuts down
+    spinning.store(false, Ordering::Relaxed);
+    spinner.await?;
+    Ok(())
+}
+
+/// What the lifecycle transitions act on.
+struct VideoNode {
+    logger: String,
+    node_params: Arc<Mutex<NodeParams>>,
+    outputs: Arc<Outputs>,
+    reconnect: Arc<watch::Sender<u64>>,
+    /// Deactivates the node when streaming fails for good
+    lifecycle: Lifecycle,
+    /// Built by configure, the first session of the next activation uses them
+    /// unless a session parameter changed since, counted by `reconnect`
+    configured: Option<(SessionSettings, u64)>,
+    /// Runs from activate until cleanup or shutdown, deactivate keeps the session up
+    supervisor: Option<JoinHandle<()>>,
+}
+
+impl VideoNode {
+    fn transition(&mut self, transition: Transition) -> Result<()> {
+        match transition {
+            Transition::Configure => {
+                let node_params = self.node_params.lock().unwrap();
+                node_params.validate()?;
+                let mut settings = node_params.session_settings()?;
+                if let Source::WebRtc = node_params.source()? {
+                    settings.source = settings.source.prepare_sdp(sdp::DYNAMIC_PAYLOAD_TYPE)?;
+                }
+                self.configured = Some((settings, *self.reconnect.borrow()));
+                Ok(())
+            }
+            Transition::Activate => {
+                // A supervisor that failed has deactivated the node, start over
+                if self
+                    .supervisor
+                    .as_ref()
+                    .map_or(true, |supervisor| supervisor.is_finished())
+                {
+                    let logger = self.logger.clone();
+                    let lifecycle = self.lifecycle.clone();
+                    let supervisor = supervise(
+                        logger.clone(),
+                        self.node_params.clone(),
+                        self.outputs.clone(),
+                        self.reconnect.subscribe(),
+                        self.configured.take().and_then(|(settings, generation)| {
+                            (generation == *self.reconnect.borrow()).then_some(settings)
+                        }),
+                    );
+                    self.supervisor = Some(task::spawn(async move {
+                        if let Err(e) = supervisor.await {
+                            r2r::log_error!(&logger, "Stopped streaming, deactivating: {:#}", e);
+                            lifecycle.trigger(Transition::Deactivate).await;
+                        }
+                    }));
+                }
+                self.outputs.active.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+            Transition::Deactivate => {
+                self.outputs.active.store(false, Ordering::Relaxed);
+                Ok(())
+            }
+            Transition::Cleanup | Transition::Shutdown => {
+                self.outputs.active.store(false, Ordering::Relaxed);
+                self.configured = None;
+                // Dropping the session ends the stream, see webrtc::Session
+                if let Some(supervisor) = self.supervisor.take() {
+                    supervisor.abort();
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Keeps a session running, reconnecting with backoff when it ends.
+///
+/// The first session uses the `configured` settings, later ones read the
+/// parameters again.
+async fn supervise(
+    logger: String,
+    node_params: Arc<Mutex<NodeParams>>,
+    outputs: Arc<Outputs>,
+    mut reconnect: watch::Receiver<u64>,
+    mut configured: Option<SessionSettings>,
+) -> Result<()> {
+    let mut clock = r2r::Clock::create(r2r::ClockType::RosTime)?;
+    let mut attempt: u32 = 0;
+    let mut failures: u32 = 0;
+    loop {
+        let source = node_params.lock().unwrap().source()?;
+        let settings = match configured.take() {
+            Some(settings) => Ok(settings),
+            None => node_params.lock().unwrap().session_settings(),
+        };
+        let stream = async {
+            let settings = settings?;
+            match &source {
+                Source::WebRtc => {
+                    attempt += 1;
+                    r2r::log_info!(
+                        &logger,
+                        "Connecting to the robot's video stream (attempt {})",
+                        attempt
+                    );
+                    stream_video(&logger, &node_params, &outputs, settings, &mut clock).await
+                }
+                Source::Replay(replay) => {
+                    replay_video(
+                        &logger,
+                        &node_params,
+                        &outputs,
+                        settings,
+                        replay,
+                        &mut clock,
+                    )
+                    .await
+                }
+            }
+        };
+
+        tokio::select! {
+            result = stream => match result {
+                // A replay without looping is done, until a parameter change starts it over
+                Ok(_) if matches!(&source, Source::Replay(settings) if !settings.looping) => {
+                    let _ = reconnect.changed().await;
+                    failures = 0;
+                    continue;
+                }
+                Ok(frames) if frames > 0 => {
+                    r2r::log_warn!(&logger, "Video stream ended after {} frames", frames);
+                    failures = 0;
+                }
+                Ok(_) => r2r::log_warn!(&logger, "Video stream ended before the first frame"),
+                Err(e) => r2r::log_error!(&logger, "Video stream failed: {:#}", e),
+            },
+            // Dropping the session ends the stream, see webrtc::Session
+            _ = reconnect.changed() => {
+                failures = 0;
+                continue;
+            }
+        }
+
+        failures += 1;
+        let delay = node_params.lock().unwrap().reconnect_delay(failures)?;
+        r2r::log_info!(&logger, "Reconnecting in {:.1}s", delay.as_secs_f64());
+        tokio::select! {
+            _ = time::sleep(delay) => {}
+            _ = reconnect.changed() => failures = 0,
+        }
+    }
+}
+
+/// Reconnects when a connection related parameter changes.
+async fn handle_parameter_events(
+    logger: String,
+    mut events: impl Stream<Item = (String, ParameterValue)> + Unpin,
+    reconnect: Arc<watch::Sender<u64>>,
+    changed: watch::Sender<()>,
+) {
+    while let Some((name, value)) = events.next().await {
+        changed.send_replace(());
+        if SESSION_PARAMS.contains(&name.as_str()) {
+            // Without a running supervisor this only outdates the configured settings;
+            // a supervisor subscribes when it starts, so it never sees older changes
+            reconnect.send_modify(|generation| *generation += 1);
+            if reconnect.receiver_count() > 0 {
+                r2r::log_info!(&logger, "{} changed to {:?}, reconnecting", name, value);
+            } else {
+                r2r::log_info!(&logger, "{} changed to {:?}", name, value);
+            }
+        } else if STARTUP_PARAMS.contains(&name.as_str()) {
+            r2r::log_warn!(
+                &logger,
+                "{} changed to {:?}, takes effect after a restart",
+                name,
+                value
+            );
+        } else {
+            r2r::log_info!(&logger, "{} changed to {:?}", name, value);
+        }
+    }
+}
+
+struct Outputs {
+    image: Publisher<Image>,
+    camera_info: Publisher<CameraInfo>,
+    /// Current calibration, replaced by the set_camera_info service
+    calibration: Arc<Mutex<CameraInfo>>,
+    /// Latest published image, picked up by the compressed publisher and snapshots
+    latest: watch::Sender<Option<Arc<PublishedImage>>>,
+    /// Frame buffers, back in the pool once the latest image is replaced
+    pool: pool::BufferPool,
+    /// Arrival of the last keyframe, for snapshots that wait for a fresh frame
+    keyframe_at: watch::Sender<Option<Instant>>,
+    /// Switched by the start/stop_recording services
+    recording: watch::Sender<bool>,
+    /// Marked changed on every parameter event, for threads that keep settings around
+    params_changed: watch::Receiver<()>,
+    /// Event clips waiting to be started, each answered with the clip's path
+    clip_requests: Mutex<Vec<oneshot::Sender<Result<PathBuf>>>>,
+    /// Encoded video passed through without decoding, for Foxglove;
+    /// ffmpeg_image_transport's own packet message is not published
+    h264: Publisher<CompressedVideo>,
+    audio: Publisher<AudioData>,
+    audio_stamped: Publisher<AudioDataStamped>,
+    audio_info: Publisher<AudioInfo>,
+    /// Total number of decoded frames the publisher skipped because it fell behind
+    dropped_frames: Publisher<UInt64>,
+    stats: Arc<diagnostics::Stats>,
+    /// Cleared while the node is deactivated, the session keeps running
+    active: AtomicBool,
+    /// Set while frames come from the robot; replayed frames have no keyframe tracking
+    webrtc: AtomicBool,
+}
+
+struct PublishedImage {
+    image: Image,
+    /// When the packet that completed the frame was read from the stream
+    received_at: Instant,
+    pool: pool::BufferPool,
+}
+
+impl PublishedImage {
+    fn new(pool: pool::BufferPool) -> PublishedImage {
+        PublishedImage {
+            image: Image {
+                is_bigendian: cfg!(target_endian = "big").into(),
+                ..Image::default()
+            },
+            received_at: Instant::now(),
+            pool,
+        }
+    }
+}
+
+impl Drop for PublishedImage {
+    fn drop(&mut self) {
+        self.pool.put(mem::take(&mut self.image.data));
+    }
+}
+
+/// Decodes and publishes the audio track of a session until the session ends.
+///
+/// Decoding starts once `publish_audio` is set; audio is published while it stays set.
+async fn stream_audio(
+    logger: String,
+    mut state: watch::Receiver<webrtc::SessionState>,
+    audio_received: impl std::future::Future<Output = Result<u8>>,
+    audio_port: u16,
+    outputs: Arc<Outputs>,
+    node_params: Arc<Mutex<NodeParams>>,
+) -> Result<()> {
+    let mut params_changed = outputs.params_changed.clone();
+    loop {
+        let enabled = node_params.lock().unwrap().publish_audio;
+        if enabled {
+            break;
+        }
+        tokio::select! {
+            changed = params_changed.changed() => changed?,
+            _ = state.wait_for(|s| matches!(s, webrtc::SessionState::Failed(_))) => return Ok(()),
+        }
+    }
+
+    let payload_type = audio_received.await?;
+    let codec = sdp::Opus {
+        payload_type,
+        channels: 2,
+    };
+    let sdp_file = sdp::SdpFile::create("audio", &sdp::audio(audio_port, &codec))?;
+    let options = node_params.lock().unwrap().decoder_options()?;
+
+    task::spawn_blocking(move || {
+        let mut clock = r2r::Clock::create(r2r::ClockType::RosTime)?;
+        let mut stamping = Stamping::new(&logger, &outputs, &node_params);
+        let mut enabled = true;
+        let mut format = None;
+
+        audio::decode(&logger, sdp_file.path(), &options, |chunk| {
+            if format != Some(chunk.format) {
+                format = Some(chunk.format);
+                let _ = outputs.audio_info.publish(&AudioInfo {
+                    channels: chunk.format.channels as u8,
+                    sample_rate: chunk.format.sample_rate,
+                    sample_format: audio::PcmFormat::SAMPLE_FORMAT.to_string(),
+                    bitrate: 0,
+                    coding_format: "wave".to_string(),
+                });
+            }
+
+            if params_changed.has_changed().unwrap_or(false) {
+                params_changed.borrow_and_update();
+                enabled = node_params.lock().unwrap().publish_audio;
+            }
+            if !enabled || !outputs.active.load(Ordering::Relaxed) {
+                return;
+            }
+            let Ok(now) = clock.get_now() else {
+                return;
             };
+            let stamp = stamping.stamp(&node_params, chunk.time, now);
+
+            let audio = AudioData { data: chunk.data };
+            let mut stamped = AudioDataStamped::default();
+            stamped.header.stamp = r2r::Clock::to_builtin_time(&stamp);
+            stamped.header.frame_id = node_params.lock().unwrap().frame_id.clone();
+            stamped.audio = audio;
 
-            go2webrtc_rs::run(
-                video_port,
-                audio_port,
-                &robot_ip,
-                &robot_token,
-                debug_webrtc,
+            let _ = outputs.audio.publish(&stamped.audio);
+            let _ = outputs.audio_stamped.publish(&stamped);
+        })
+    })
+    .await?
+}
+
+/// Publishes the encoded video as it arrives, starting at the first keyframe,
+/// as `foxglove_msgs/CompressedVideo` so Foxglove can play it.
+///
+/// Returns the number of published access units once the session ends.
+/// With `count_frames` they also count as published frames in the diagnostics,
+/// for when nothing is decoded.
+async fn publish_h264(
+    logger: String,
+    mut access_units: broadcast::Receiver<Arc<h264::AccessUnit>>,
+    outputs: Arc<Outputs>,
+    node_params: Arc<Mutex<NodeParams>>,
+    count_frames: bool,
+) -> Result<u64> {
+    let mut clock = r2r::Clock::create(r2r::ClockType::RosTime)?;
+    let mut stamping = Stamping::new(&logger, &outputs, &node_params);
+    let mut rtp_time = stamp::RtpTime::default();
+    let mut msg = CompressedVideo {
+        format: "h264".to_string(),
+        ..Default::default()
+    };
+
+    let mut published = 0;
+    let mut waiting_for_keyframe = true;
+    loop {
+        let access_unit = match access_units.recv().await {
+            Ok(access_unit) => access_unit,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                // Everything up to the next keyframe references what was skipped
+                r2r::log_warn!(&logger, "Skipped {} H.264 access units", skipped);
+                waiting_for_keyframe = true;
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        if !outputs.active.load(Ordering::Relaxed) {
+            // Resume with a decodable access unit after reactivation
+            waiting_for_keyframe = true;
+            continue;
+        }
+        // Pictures after a loss reference what is missing, until the next keyframe
+        if (waiting_for_keyframe || access_unit.after_loss) && !access_unit.keyframe {
+            waiting_for_keyframe = true;
+            continue;
+        }
+        waiting_for_keyframe = false;
+
+        let now = clock.get_now()?;
+        let stream_time = rtp_time.seconds(access_unit.rtp_timestamp, 90000);
+        let stamp = stamping.stamp(&node_params, Some(stream_time), now);
+
+        msg.timestamp = r2r::Clock::to_builtin_time(&stamp);
+        msg.frame_id = node_params.lock().unwrap().frame_id.clone();
+        msg.data = access_unit.data.clone();
+        let _ = outputs.h264.publish(&msg);
+        if count_frames {
+            outputs
+                .stats
+                .frames_published
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        published += 1;
+    }
+
+    Ok(published)
+}
+
+/// Publishes the health of the pipeline on /diagnostics.
+async fn publish_diagnostics(
+    publisher: Publisher<DiagnosticArray>,
+    outputs: Arc<Outputs>,
+    node_params: Arc<Mutex<NodeParams>>,
+) -> Result<()> {
+    let mut clock = r2r::Clock::create(r2r::ClockType::RosTime)?;
+    let mut reporter = diagnostics::Reporter::new(outputs.stats.clone());
+    loop {
+        let (period, thresholds, hardware_id) = {
+            let np = node_params.lock().unwrap();
+            (
+                seconds("diagnostics_period", np.diagnostics_period)
+                    .unwrap_or(Duration::from_secs(1)),
+                np.diagnostic_thresholds(),
+                np.robot_ip.clone(),
             )
-            .await
+        };
+        time::sleep(period).await;
+
+        let mut msg = DiagnosticArray::default();
+        msg.header.stamp = r2r::Clock::to_builtin_time(&clock.get_now()?);
+        msg.status = reporter.report(
+            outputs.active.load(Ordering::Relaxed),
+            &hardware_id,
+            &thresholds,
+        );
+        let _ = publisher.publish(&msg);
+    }
+}
+
+/// Compresses the latest image off the decode path.
+///
+/// Images published while an encode is running are skipped, so a slow encoder
+/// lowers the compressed frame rate instead of delaying the raw topic.
+async fn publish_compressed(
+    logger: String,
+    mut images: watch::Receiver<Option<Arc<PublishedImage>>>,
+    publisher: Publisher<CompressedImage>,
+    node_params: Arc<Mutex<NodeParams>>,
+) {
+    // Parameters and encodings change rarely, so a bad one is logged once
+    // instead of for every image until it changes
+    let mut failing = false;
+    while images.changed().await.is_ok() {
+        let Some(published) = images.borrow_and_update().clone() else {
+            continue;
+        };
+        let format = match node_params.lock().unwrap().compressed_format() {
+            Ok(Some(format)) => format,
+            Ok(None) => {
+                failing = false;
+                continue;
+            }
+            Err(e) => {
+                if !mem::replace(&mut failing, true) {
+                    r2r::log_error!(&logger, "{:#}", e);
+                }
+                continue;
+            }
+        };
+
+        match task::spawn_blocking(move || compressed::encode(&published.image, format)).await {
+            Ok(Ok(msg)) => {
+                failing = false;
+                let _ = publisher.publish(&msg);
+            }
+            Ok(Err(e)) => {
+                if !mem::replace(&mut failing, true) {
+                    r2r::log_error!(&logger, "Failed to compress image: {:#}", e);
+                }
+            }
+            Err(e) => r2r::log_error!(&logger, "Compression task failed: {}", e),
         }
-    });
+    }
+}
+
+/// Answers with a single frame, also published on ~/snapshot/image, after saving
+/// it to the requested path or `snapshot_path`.
+async fn serve_snapshot(
+    logger: String,
+    mut requests: impl Stream<Item = ServiceRequest<Snapshot::Service>> + Unpin,
+    publisher: Publisher<Image>,
+    outputs: Arc<Outputs>,
+    node_params: Arc<Mutex<NodeParams>>,
+) {
+    while let Some(request) = requests.next().await {
+        let path = request.message.path.clone();
+        let response = match take_snapshot(&publisher, &outputs, &node_params, path).await {
+            Ok((image, saved)) => {
+                let path = match saved {
+                    Some(saved) => {
+                        r2r::log_info!(&logger, "Snapshot saved to {}", saved.display());
+                        saved.display().to_string()
+                    }
+                    None => {
+                        r2r::log_info!(&logger, "Snapshot taken");
+                        String::new()
+                    }
+                };
+                Snapshot::Response {
+                    success: true,
+                    message: String::new(),
+                    path,
+                    image,
+                }
+            }
+            Err(e) => {
+                r2r::log_warn!(&logger, "Snapshot failed: {:#}", e);
+                Snapshot::Response {
+                    success: false,
+                    message: format!("{:#}", e),
+                    ..Snapshot::Response::default()
+                }
+            }
+        };
+        let _ = request.respond(response);
+    }
+}
+
+/// Publishes a frame and saves it to `path`, or to `snapshot_path` if `path` is empty.
+///
+/// Returns the frame and where it was saved, if it was.
+async fn take_snapshot(
+    publisher: &Publisher<Image>,
+    outputs: &Outputs,
+    node_params: &Arc<Mutex<NodeParams>>,
+    path: String,
+) -> Result<(Image, Option<PathBuf>)> {
+    if !outputs.active.load(Ordering::Relaxed) {
+        bail!("node is not active");
+    }
+    let (path, wait_fresh, timeout, jpeg_quality, png_level) = {
+        let np = node_params.lock().unwrap();
+        (
+            if path.is_empty() {
+                np.snapshot_path.clone()
+            } else {
+                path
+            },
+            np.snapshot_wait_fresh,
+            seconds("snapshot_timeout", np.snapshot_timeout)?,
+            np.jpeg_quality,
+            np.png_level,
+        )
+    };
+
+    let requested = Instant::now();
+    let mut keyframe_at = outputs.keyframe_at.subscribe();
+    let mut latest = outputs.latest.subscribe();
+    let published = time::timeout(timeout, async {
+        // A frame decoded after a keyframe is free of artifacts from earlier packet loss;
+        // replays have no keyframe tracking and no packet loss, the next frame will do
+        let not_before = if wait_fresh && !outputs.webrtc.load(Ordering::Relaxed) {
+            Some(requested)
+        } else if wait_fresh {
+            let keyframe = keyframe_at
+                .wait_for(|at| matches!(at, Some(at) if *at > requested))
+                .await?;
+            Some((*keyframe).unwrap())
+        } else {
+            None
+        };
+        let published = latest
+            .wait_for(|published| match (published, not_before) {
+                (Some(published), Some(not_before)) => published.received_at > not_before,
+                (Some(_), None) => true,
+                (None, _) => false,
+            })
+            .await?;
+        Ok::<_, anyhow::Error>((*published).clone().unwrap())
+    })
+    .await
+    .map_err(|_| anyhow!("no frame within {:.1}s", timeout.as_secs_f64()))??;
+
+    let _ = publisher.publish(&published.image);
+    if path.is_empty() {
+        return Ok((published.image.clone(), None));
+    }
+
+    let path = snapshot::path(&path, &published.image.header.stamp);
+    let saving = published.clone();
+    let saved = path.clone();
+    task::spawn_blocking(move || snapshot::save(&saving.image, &path, jpeg_quality, png_level))
+        .await??;
+    Ok((published.image.clone(), Some(saved)))
+}
+
+/// Turns recording on or off, depending on which service `requests` belongs to.
+///
+/// Recording only starts while the node is active and streaming from the robot,
+/// the recorder writes the encoded video of the WebRTC session.
+async fn serve_recording_switch(
+    mut requests: impl Stream<Item = ServiceRequest<Trigger::Service>> + Unpin,
+    on: bool,
+    outputs: Arc<Outputs>,
+    node_params: Arc<Mutex<NodeParams>>,
+) {
+    while let Some(request) = requests.next().await {
+        let unavailable = if !outputs.active.load(Ordering::Relaxed) {
+            Some("node is not active")
+        } else if !outputs.webrtc.load(Ordering::Relaxed) {
+            Some("no video from the robot to record, replays cannot be recorded")
+        } else {
+            None
+        };
+        let response = match (unavailable, node_params.lock().unwrap().record_settings()) {
+            (Some(reason), _) if on => Trigger::Response {
+                success: false,
+                message: reason.to_string(),
+            },
+            (_, Err(e)) if on => Trigger::Response {
+                success: false,
+                message: format!("{:#}", e),
+            },
+            (_, settings) => {
+                let was_on = outputs.recording.send_replace(on);
+                let message = match (on, was_on, settings) {
+                    (true, true, _) => "already recording".to_string(),
+                    (true, false, Ok(settings)) => {
+                        format!("recording to {}", settings.directory.display())
+                    }
+                    (false, true, _) => "stopped recording".to_string(),
+                    _ => "not recording".to_string(),
+                };
+                Trigger::Response {
+                    success: true,
+                    message,
+                }
+            }
+        };
+        let _ = request.respond(response);
+    }
+}
+
+/// Answers with the path of the clip once its post-roll is written.
+async fn serve_save_clip(
+    logger: String,
+    mut requests: impl Stream<Item = ServiceRequest<Trigger::Service>> + Unpin,
+    outputs: Arc<Outputs>,
+    node_params: Arc<Mutex<NodeParams>>,
+) {
+    while let Some(request) = requests.next().await {
+        let (enabled, post_roll) = {
+            let np = node_params.lock().unwrap();
+            (np.clip_enabled, np.clip_post_roll.max(0.0))
+        };
+        if !enabled {
+            let _ = request.respond(Trigger::Response {
+                success: false,
+                message: "clips are disabled, see clip_enabled".to_string(),
+            });
+            continue;
+        }
+        let (done, clip) = oneshot::channel();
+        outputs.clip_requests.lock().unwrap().push(done);
+
+        // Generous, the clip only starts with the next access unit of a running session
+        let timeout = Duration::from_secs_f64(post_roll) + Duration::from_secs(10);
+        let response = match time::timeout(timeout, clip).await {
+            Ok(Ok(Ok(path))) => Trigger::Response {
+                success: true,
+                message: path.display().to_string(),
+            },
+            Ok(Ok(Err(e))) => Trigger::Response {
+                success: false,
+                message: format!("{:#}", e),
+            },
+            Ok(Err(_)) | Err(_) => Trigger::Response {
+                success: false,
+                message: "no video to record".to_string(),
+            },
+        };
+        if !response.success {
+            r2r::log_warn!(&logger, "Clip failed: {}", response.message);
+        }
+        let _ = request.respond(response);
+    }
+}
+
+/// Saves a clip for every message on the trigger topic.
+async fn handle_clip_triggers(
+    logger: String,
+    mut triggers: impl Stream<Item = Empty> + Unpin,
+    outputs: Arc<Outputs>,
+    node_params: Arc<Mutex<NodeParams>>,
+) {
+    while triggers.next().await.is_some() {
+        if !node_params.lock().unwrap().clip_enabled {
+            r2r::log_warn!(&logger, "Clip trigger ignored, clips are disabled");
+            continue;
+        }
+        let (done, clip) = oneshot::channel();
+        outputs.clip_requests.lock().unwrap().push(done);
+        let logger = logger.clone();
+        task::spawn(async move {
+            if let Ok(Err(e)) = clip.await {
+                r2r::log_warn!(&logger, "Clip failed: {:#}", e);
+            }
+        });
+    }
+}
+
+/// A clip being written until the post-roll is complete.
+struct ActiveClip {
+    recorder: record::Recorder,
+    /// Stream time at which the clip ends
+    end: f64,
+    requests: Vec<oneshot::Sender<Result<PathBuf>>>,
+}
+
+impl ActiveClip {
+    fn finish(mut self) {
+        let path = self.recorder.path().map(Path::to_path_buf);
+        let result = match (self.recorder.stop(), path) {
+            (Ok(()), Some(path)) => Ok(path),
+            (Ok(()), None) => Err(anyhow!("no keyframe to start the clip with")),
+            (Err(e), _) => Err(e),
+        };
+        for request in self.requests {
+            let _ = request.send(result.as_ref().cloned().map_err(|e| anyhow!("{:#}", e)));
+        }
+    }
 
-    // TODO: wait for the webrtc connection to be established
-    time::sleep(std::time::Duration::from_secs(3)).await;
+    fn fail(self, error: anyhow::Error) {
+        for request in self.requests {
+            let _ = request.send(Err(anyhow!("{:#}", error)));
+        }
+    }
+}
+
+/// Keeps the pre-roll of a session and writes event clips from it.
+///
+/// Runs on its own thread until the session ends, as muxing does blocking file IO.
+fn record_clips(
+    logger: String,
+    mut access_units: broadcast::Receiver<Arc<h264::AccessUnit>>,
+    outputs: Arc<Outputs>,
+    node_params: Arc<Mutex<NodeParams>>,
+) {
+    let mut pre_roll = clip::PreRoll::new();
+    let mut rtp_time = stamp::RtpTime::default();
+    let mut active: Option<ActiveClip> = None;
+    let mut params_changed = outputs.params_changed.clone();
+    params_changed.borrow_and_update();
+    let clip_params = |np: &NodeParams| (np.clip_pre_roll, np.clip_post_roll, np.clip_settings());
+    let (mut pre_roll_duration, mut post_roll, mut settings) =
+        clip_params(&node_params.lock().unwrap());
+    loop {
+        let access_unit = match access_units.blocking_recv() {
+            Ok(access_unit) => access_unit,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                r2r::log_warn!(&logger, "Clip buffer skipped {} access units", skipped);
+                pre_roll.clear();
+                if let Some(clip) = &mut active {
+                    clip.recorder.resync();
+                }
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        let time = rtp_time.seconds(access_unit.rtp_timestamp, 90000);
+
+        if params_changed.has_changed().unwrap_or(false) {
+            params_changed.borrow_and_update();
+            (pre_roll_duration, post_roll, settings) = clip_params(&node_params.lock().unwrap());
+        }
+        pre_roll.push(time, access_unit.clone(), pre_roll_duration);
+
+        let requests: Vec<_> = mem::take(&mut *outputs.clip_requests.lock().unwrap())
+            .into_iter()
+            .filter(|request| !request.is_closed())
+            .collect();
+        let settings = match &settings {
+            Ok(settings) => settings,
+            Err(e) => {
+                for request in requests {
+                    let _ = request.send(Err(anyhow!("{:#}", e)));
+                }
+                continue;
+            }
+        };
+
+        // A trigger during a clip extends it instead of starting another one
+        let result = if let Some(clip) = &mut active {
+            if !requests.is_empty() {
+                clip.end = time + post_roll;
+                clip.requests.extend(requests);
+            }
+            clip.recorder.write(&access_unit, settings)
+        } else if !requests.is_empty() {
+            r2r::log_info!(&logger, "Saving event clip");
+            let clip = active.insert(ActiveClip {
+                recorder: record::Recorder::new(logger.clone()),
+                end: time + post_roll,
+                requests,
+            });
+            pre_roll
+                .iter()
+                .try_for_each(|unit| clip.recorder.write(unit, settings))
+        } else {
+            Ok(())
+        };
+
+        match result {
+            Err(e) => active.take().unwrap().fail(e),
+            Ok(()) if active.as_ref().is_some_and(|clip| time >= clip.end) => {
+                active.take().unwrap().finish()
+            }
+            Ok(()) => {}
+        }
+    }
+
+    // The session ended, keep what was recorded of the post-roll
+    if let Some(clip) = active {
+        clip.finish();
+    }
+}
+
+/// Starts a recording thread whenever recording is switched on, until the session ends.
+async fn switch_recording(
+    logger: String,
+    access_units: broadcast::Receiver<Arc<h264::AccessUnit>>,
+    mut state: watch::Receiver<webrtc::SessionState>,
+    outputs: Arc<Outputs>,
+    node_params: Arc<Mutex<NodeParams>>,
+) {
+    let mut recording = outputs.recording.subscribe();
+    let mut thread: Option<std::thread::JoinHandle<()>> = None;
+    loop {
+        tokio::select! {
+            _ = recording.wait_for(|on| *on) => {}
+            _ = state.wait_for(|s| matches!(s, webrtc::SessionState::Failed(_))) => break,
+        }
+        // A thread switched off a moment ago may not have noticed yet, it just carries on
+        if thread.as_ref().map_or(true, |thread| thread.is_finished()) {
+            let spawned = std::thread::Builder::new()
+                .name("go2_video_record".to_string())
+                .spawn({
+                    let logger = logger.clone();
+                    let access_units = access_units.resubscribe();
+                    let outputs = outputs.clone();
+                    let node_params = node_params.clone();
+                    move || record_video(logger, access_units, outputs, node_params)
+                });
+            match spawned {
+                Ok(spawned) => thread = Some(spawned),
+                Err(e) => {
+                    r2r::log_error!(&logger, "Failed to start recording: {}", e);
+                    outputs.recording.send_replace(false);
+                }
+            }
+        }
+        tokio::select! {
+            _ = recording.wait_for(|on| !*on) => {}
+            _ = state.wait_for(|s| matches!(s, webrtc::SessionState::Failed(_))) => break,
+        }
+    }
+}
+
+/// Writes the encoded video of a session to files until recording is switched off.
+///
+/// Runs on its own thread until then or until the session ends, as muxing does
+/// blocking file IO. The settings are read again when parameters change.
+fn record_video(
+    logger: String,
+    mut access_units: broadcast::Receiver<Arc<h264::AccessUnit>>,
+    outputs: Arc<Outputs>,
+    node_params: Arc<Mutex<NodeParams>>,
+) {
+    let mut recorder = record::Recorder::new(logger.clone());
+    let mut params_changed = outputs.params_changed.clone();
+    params_changed.borrow_and_update();
+    let mut settings = node_params.lock().unwrap().record_settings();
+    loop {
+        let access_unit = match access_units.blocking_recv() {
+            Ok(access_unit) => access_unit,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                r2r::log_warn!(&logger, "Recording skipped {} access units", skipped);
+                recorder.resync();
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        if !*outputs.recording.borrow() {
+            if let Err(e) = recorder.stop() {
+                r2r::log_error!(&logger, "Failed to finish recording: {:#}", e);
+            }
+            return;
+        }
+
+        if params_changed.has_changed().unwrap_or(false) {
+            params_changed.borrow_and_update();
+            settings = node_params.lock().unwrap().record_settings();
+        }
+        let result = match &settings {
+            Ok(settings) => recorder.write(&access_unit, settings),
+            Err(e) => Err(anyhow!("{:#}", e)),
+        };
+        if let Err(e) = result {
+            r2r::log_error!(&logger, "Recording failed, stopping: {:#}", e);
+            outputs.recording.send_replace(false);
+            return;
+        }
+    }
+}
+
+async fn serve_set_camera_info(
+    logger: String,
+    mut requests: impl Stream<Item = ServiceRequest<SetCameraInfo::Service>> + Unpin,
+    calibration: Arc<Mutex<CameraInfo>>,
+    path: PathBuf,
+    camera_name: String,
+) {
+    while let Some(request) = requests.next().await {
+        let info = request.message.camera_info.clone();
+        let response = match camera_info::save(&path, &camera_name, &info) {
+            Ok(()) => {
+                r2r::log_info!(&logger, "Saved new calibration to {}", path.display());
+                *calibration.lock().unwrap() = info;
+                SetCameraInfo::Response {
+                    success: true,
+                    status_message: format!("saved to {}", path.display()),
+                }
+            }
+            Err(e) => {
+                r2r::log_error!(&logger, "Failed to save calibration: {:#}", e);
+                SetCameraInfo::Response {
+                    success: false,
+                    status_message: format!("{:#}", e),
+                }
+            }
+        };
+        let _ = request.respond(response);
+    }
+}
 
-    //let source = Locator::Url("file:///tmp/connection.sdp".parse().unwrap());
-    let source = Locator::Url(
-        "https://raw.githubusercontent.com/tfoldi/go2webrtc-rs/master/connection.sdp"
-            .parse()
-            .unwrap(),
+/// Runs a single WebRTC session and publishes its frames until the stream ends.
+///
+/// Returns the number of published frames.
+async fn stream_video(
+    logger: &str,
+    node_params: &Arc<Mutex<NodeParams>>,
+    outputs: &Arc<Outputs>,
+    settings: SessionSettings,
+    clock: &mut r2r::Clock,
+) -> Result<u64> {
+    outputs.webrtc.store(true, Ordering::Relaxed);
+    let mut video = settings
+        .source
+        .clone()
+        .traffic(outputs.stats.video.clone())
+        .buffer_pool(outputs.pool.clone())
+        .logger(logger)
+        .start()
+        .await?;
+    // The configured source may predate changes to the encoding or transform
+    video
+        .output()
+        .send_replace(node_params.lock().unwrap().output()?);
+    let session = video.session();
+    task::spawn(log_session_state(
+        logger.to_string(),
+        session.state(),
+        outputs.stats.clone(),
+    ));
+
+    let audio = stream_audio(
+        logger.to_string(),
+        session.state(),
+        session.wait_for_audio(),
+        video.audio_port(),
+        outputs.clone(),
+        node_params.clone(),
     );
+    task::spawn({
+        let logger = logger.to_string();
+        async move {
+            if let Err(e) = audio.await {
+                r2r::log_warn!(&logger, "Audio stream ended: {:#}", e);
+            }
+        }
+    });
 
-    r2r::log_debug!(node.logger(), "Opening local stream");
+    // Only open the stream once RTP is actually arriving on the video port
+    video.wait_for_video().await?;
+    let session = video.session();
 
-    let decoder_options = get_decoder_options();
-    let mut decoder =
-        Decoder::new_with_options(&source, &decoder_options).expect("failed to create decoder");
+    task::spawn(switch_recording(
+        logger.to_string(),
+        session.access_units(),
+        session.state(),
+        outputs.clone(),
+        node_params.clone(),
+    ));
+    if settings.clip_enabled {
+        std::thread::Builder::new()
+            .name("go2_video_clips".to_string())
+            .spawn({
+                let logger = logger.to_string();
+                let access_units = session.access_units();
+                let outputs = outputs.clone();
+                let node_params = node_params.clone();
+                move || record_clips(logger, access_units, outputs, node_params)
+            })?;
+    }
+
+    let h264_task = settings.video_output.h264().then(|| {
+        task::spawn(publish_h264(
+            logger.to_string(),
+            session.access_units(),
+            outputs.clone(),
+            node_params.clone(),
+            !settings.video_output.raw(),
+        ))
+    });
+    if !settings.video_output.raw() {
+        // Nothing to decode, the session ends when the relay stops forwarding video
+        return h264_task.unwrap().await?;
+    }
+
+    task::spawn(track_keyframes(session.access_units(), outputs.clone()));
+
+    let mut watchdog = watchdog::Watchdog::new(settings.watchdog);
+    let mut published = 0;
+    loop {
+        let frames = video.frames().await?;
+        published += publish_frames(
+            logger,
+            frames,
+            outputs,
+            node_params,
+            video.output(),
+            Some((&mut watchdog, video.session())),
+            clock,
+        )
+        .await?;
+
+        // Only a decoder the watchdog stopped is started again, otherwise the stream is over
+        if !watchdog.take_resync() {
+            return Ok(published);
+        }
+        r2r::log_info!(logger, "Restarting the decoder");
+    }
+}
 
-    r2r::log_info!(node.logger(), "Input stream size: {:?}", decoder.size());
-    r2r::log_info!(node.logger(), "Output stream size {:?}", decoder.size_out());
+/// Replays a file or URL in place of the robot's stream until it ends.
+///
+/// Only decoded images are published; H.264 output, recording, clips and audio
+/// need the WebRTC session.
+///
+/// Returns the number of published frames.
+async fn replay_video(
+    logger: &str,
+    node_params: &Arc<Mutex<NodeParams>>,
+    outputs: &Arc<Outputs>,
+    settings: SessionSettings,
+    replay: &replay::Settings,
+    clock: &mut r2r::Clock,
+) -> Result<u64> {
+    outputs.webrtc.store(false, Ordering::Relaxed);
+    if !settings.video_output.raw() {
+        r2r::log_warn!(
+            logger,
+            "video_output {:?} is not available when replaying, publishing decoded images",
+            settings.video_output
+        );
+    }
 
-    // Create a publisher for the image topic
-    let publisher = node.create_publisher::<Image>("/go2_camera/color/image", QosProfile::default())?;
+    // The RTP defaults do not apply to files and other protocols
+    let decoder_options = node_params.lock().unwrap().decoder_option_overrides()?;
+    options::validate(&decoder_options)?;
+    r2r::log_info!(
+        logger,
+        "Decoder options: {}",
+        options::describe(&decoder_options)
+    );
 
-    // Create an Image message
-    let mut image_msg = Image::default();
-    let (width, height) = decoder.size_out();
+    let output = watch::channel(node_params.lock().unwrap().output()?).0;
+    let (frames_tx, frames_rx) = latest::channel();
+    replay::spawn(
+        logger.to_string(),
+        replay.clone(),
+        decoder_options,
+        output.subscribe(),
+        outputs.pool.clone(),
+        frames_tx,
+    )?;
+    publish_frames(
+        logger,
+        Frames::from(frames_rx),
+        outputs,
+        node_params,
+        &output,
+        None,
+        clock,
+    )
+    .await
+}
 
-    image_msg.header.frame_id = "front_camera".to_string();
-    image_msg.width = width;
-    image_msg.height = height;
-    image_msg.step = image_msg.width * 3;
-    image_msg.encoding = "rgb8".to_string();
-    if cfg!(target_endian = "little") {
-        image_msg.is_bigendian = 0;
-    } else if cfg!(target_endian = "big") {
-        image_msg.is_bigendian = 1;
+/// Records when keyframes arrive, until the session ends.
+async fn track_keyframes(
+    mut access_units: broadcast::Receiver<Arc<h264::AccessUnit>>,
+    outputs: Arc<Outputs>,
+) {
+    loop {
+        match access_units.recv().await {
+            Ok(access_unit) if access_unit.keyframe => {
+                outputs
+                    .keyframe_at
+                    .send_replace(Some(access_unit.received_at));
+            }
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
     }
+}
+
+/// How often the watchdog is checked while no frames arrive.
+const WATCHDOG_INTERVAL: Duration = Duration::from_millis(250);
 
-    r2r::log_debug!(node.logger(), "Start processing frames");
-    // Process frames one by one
-    for frame in decoder.decode_iter() {
-        if let Ok((_, frame)) = frame {
-            let now = clock.get_now()?;
+/// Publishes decoded frames until the decoder thread ends.
+///
+/// Changes to the encoding and transform parameters are sent to the decoder
+/// on `output`. The watchdog, if given, is checked while waiting for frames; a resync ends
+/// the decoder stream, a restart fails the call so the session is set up again.
+///
+/// Returns the number of published frames.
+#[allow(clippy::too_many_arguments)]
+async fn publish_frames(
+    logger: &str,
+    mut frames: Frames,
+    outputs: &Outputs,
+    node_params: &Arc<Mutex<NodeParams>>,
+    output: &watch::Sender<filter::Output>,
+    mut watchdog: Option<(&mut watchdog::Watchdog, &webrtc::Session)>,
+    clock: &mut r2r::Clock,
+) -> Result<u64> {
+    // Whether the middleware lends messages does not change while publishing
+    let loan = outputs.image.can_loan_messages();
+    // The image published before the current one, reused for the next frame
+    // once nobody holds it, so neither its strings nor the Arc are allocated again
+    let mut spare: Option<Arc<PublishedImage>> = None;
+    let mut publish_failing = false;
+
+    let mut published = 0;
+    let mut dropped = 0;
+    let mut stamping = Stamping::new(logger, outputs, node_params);
+    let mut params_changed = outputs.params_changed.clone();
+    loop {
+        if params_changed.has_changed().unwrap_or(false) {
+            params_changed.borrow_and_update();
+            let next = node_params.lock().unwrap().output();
+            match next {
+                Ok(next) => {
+                    output.send_if_modified(|current| mem::replace(current, next) != next);
+                }
+                Err(e) => r2r::log_error!(logger, "{:#}", e),
+            }
+        }
 
-            image_msg.header.stamp = r2r::Clock::to_builtin_time(&now);
-            image_msg.data = frame.into_raw_vec();
+        let frame = match time::timeout(WATCHDOG_INTERVAL, frames.next()).await {
+            Ok(Some(frame)) => Some(frame),
+            Ok(None) => break,
+            Err(_) => None,
+        };
 
-            let _ = publisher.publish(&image_msg);
+        if let Some((watchdog, session)) = watchdog.as_mut() {
+            if let Some(frame) = &frame {
+                if watchdog.frame(&frame.data) {
+                    r2r::log_info!(logger, "Video stream recovered");
+                }
+            }
+            match watchdog.check() {
+                watchdog::Action::None => {}
+                watchdog::Action::Warn => r2r::log_warn!(
+                    logger,
+                    "Video frozen for {:.1}s",
+                    watchdog.frozen_for().as_secs_f64()
+                ),
+                watchdog::Action::Resync => {
+                    r2r::log_warn!(
+                        logger,
+                        "Video frozen for {:.1}s, restarting the decoder to wait for a keyframe",
+                        watchdog.frozen_for().as_secs_f64()
+                    );
+                    session.end_video_stream();
+                }
+                watchdog::Action::Restart => bail!(
+                    "video frozen for {:.1}s",
+                    watchdog.frozen_for().as_secs_f64()
+                ),
+            }
+        }
+        let Some(frame) = frame else {
+            continue;
+        };
+
+        if frame.sequence == 0 {
+            let calibration = outputs.calibration.lock().unwrap();
+            if calibration.width != 0
+                && (calibration.width, calibration.height) != frame.source_size
+            {
+                r2r::log_warn!(
+                    logger,
+                    "Calibration is for {}x{}, stream is {}x{}",
+                    calibration.width,
+                    calibration.height,
+                    frame.source_size.0,
+                    frame.source_size.1
+                );
+            }
+        }
+
+        let stats = &outputs.stats;
+        stats.frames_decoded.fetch_add(1, Ordering::Relaxed);
+        if frames.dropped() != dropped {
+            let skipped = frames.dropped() - dropped;
+            dropped = frames.dropped();
+            stats.frames_decoded.fetch_add(skipped, Ordering::Relaxed);
+            let total = stats.frames_dropped.fetch_add(skipped, Ordering::Relaxed) + skipped;
+            r2r::log_debug!(logger, "Publisher fell behind, skipped {} frames", skipped);
+            let _ = outputs.dropped_frames.publish(&UInt64 { data: total });
+        }
+
+        if !outputs.active.load(Ordering::Relaxed) {
+            outputs.pool.put(frame.data);
+            continue;
+        }
+
+        // The frame arrived with its last packet, before decoding and before
+        // we got around to it
+        let now = clock.get_now()?;
+        let received = now.saturating_sub(frame.received_at.elapsed());
+        let stamp = stamping.stamp(node_params, Some(frame.time), received);
+
+        let mut latest = match spare.take() {
+            Some(latest) if Arc::strong_count(&latest) == 1 => latest,
+            _ => Arc::new(PublishedImage::new(outputs.pool.clone())),
+        };
+        let published_image = Arc::get_mut(&mut latest).expect("spare image is not shared");
+        published_image.received_at = frame.received_at;
+        let image_msg = &mut published_image.image;
+        outputs
+            .pool
+            .put(mem::replace(&mut image_msg.data, frame.data));
+
+        // Cosmetic parameters apply from the next frame on
+        image_msg
+            .header
+            .frame_id
+            .clone_from(&node_params.lock().unwrap().frame_id);
+        image_msg.header.stamp = r2r::Clock::to_builtin_time(&stamp);
+        image_msg.width = frame.width;
+        image_msg.height = frame.height;
+        image_msg.encoding.clear();
+        image_msg.encoding.push_str(frame.encoding.name());
+        image_msg.step = frame.encoding.step(frame.width);
+
+        // camera_info goes out with the same header as the image it belongs to
+        // The calibration is kept for the source size and follows the transform
+        let mut info_msg = outputs.calibration.lock().unwrap().clone();
+        if info_msg.width == 0 {
+            info_msg.width = frame.width;
+            info_msg.height = frame.height;
+        } else if !frame.transform.is_identity() {
+            info_msg = frame
+                .transform
+                .camera_info(&info_msg, frame.width, frame.height);
+        }
+        info_msg.header = image_msg.header.clone();
+
+        let result = if loan {
+            publish_loaned(&outputs.image, image_msg)
         } else {
-            break;
+            outputs.image.publish(image_msg).map_err(Into::into)
+        };
+        match result {
+            Ok(()) => {
+                publish_failing = false;
+                stats.frame_published(
+                    frame.decoded_at.duration_since(frame.received_at),
+                    frame.received_at.elapsed(),
+                );
+            }
+            Err(e) => {
+                stats.publish_failures.fetch_add(1, Ordering::Relaxed);
+                // Once per run of failures, they tend to repeat for every frame
+                if !mem::replace(&mut publish_failing, true) {
+                    r2r::log_error!(logger, "Failed to publish image: {:#}", e);
+                }
+            }
         }
+        let _ = outputs.camera_info.publish(&info_msg);
+
+        // The compressed publisher and snapshots pick the image up from here
+        spare = outputs.latest.send_replace(Some(latest));
+        published += 1;
     }
 
+    Ok(published)
+}
+
+/// Publishes `image` in a message loaned from the middleware instead of one
+/// `publish` allocates; the pixels are still copied once, into the loan.
+///
+/// Only for publishers whose middleware lends messages; most RMWs lend none
+/// for unbounded types such as `Image`.
+fn publish_loaned(publisher: &Publisher<Image>, image: &Image) -> Result<()> {
+    let mut msg = publisher.borrow_loaned_message()?;
+    msg.header.stamp.sec = image.header.stamp.sec;
+    msg.header.stamp.nanosec = image.header.stamp.nanosec;
+    msg.header.frame_id.assign(&image.header.frame_id);
+    msg.height = image.height;
+    msg.width = image.width;
+    msg.encoding.assign(&image.encoding);
+    msg.is_bigendian = image.is_bigendian;
+    msg.step = image.step;
+    msg.data.update(&image.data);
+    publisher.publish_native(&mut msg)?;
     Ok(())
 }
@@ -0,0 +1,175 @@
+//! ffmpeg options for opening and decoding the streams.
+//!
+//! The built-in defaults suit the local RTP streams; the `decoder_options`
+//! parameter and the `decoder_options_file` YAML mapping are merged over them.
+
+use anyhow::{anyhow, bail, Context, Result};
+use ffmpeg_next as ffmpeg;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{c_void, CString};
+use std::fs;
+use std::path::Path;
+use std::ptr;
+
+pub fn defaults() -> HashMap<String, String> {
+    let mut options = HashMap::new();
+    options.insert("protocol_whitelist".to_string(), "file,rtp,udp".to_string());
+    options.insert("flags".to_string(), "low_delay".to_string());
+    options.insert("analyzeduration".to_string(), "4M".to_string());
+    options.insert("probesize".to_string(), "4M".to_string());
+    options
+}
+
+/// Parses `key=value` entries of the `decoder_options` parameter.
+pub fn parse(entries: &[String]) -> Result<HashMap<String, String>> {
+    entries
+        .iter()
+        .map(|entry| {
+            let (key, value) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("decoder option '{}' is not key=value", entry))?;
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+/// Reads a YAML mapping of option names to values.
+pub fn load(path: &Path) -> Result<HashMap<String, String>> {
+    let yaml = fs::read_to_string(path)
+        .with_context(|| format!("failed to read decoder options {}", path.display()))?;
+    let values: HashMap<String, serde_yaml::Value> = serde_yaml::from_str(&yaml)
+        .with_context(|| format!("failed to parse decoder options {}", path.display()))?;
+    values
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                serde_yaml::Value::String(value) => value,
+                serde_yaml::Value::Number(value) => value.to_string(),
+                serde_yaml::Value::Bool(value) => (value as u8).to_string(),
+                _ => bail!(
+                    "decoder option '{}' in {} is not a scalar",
+                    key,
+                    path.display()
+                ),
+            };
+            Ok((key, value))
+        })
+        .collect()
+}
+
+/// Checks that every option is known to the demuxers, protocols or decoders.
+///
+/// ffmpeg silently ignores options it does not know, so a typo would otherwise
+/// go unnoticed.
+pub fn validate(options: &HashMap<String, String>) -> Result<()> {
+    for name in options.keys() {
+        let c_name = CString::new(name.as_str())?;
+        let known = unsafe {
+            [
+                ffmpeg::ffi::avformat_get_class(),
+                ffmpeg::ffi::avcodec_get_class(),
+            ]
+            .iter()
+            .any(|class| {
+                // With AV_OPT_SEARCH_FAKE_OBJ the object is a pointer to the class
+                !ffmpeg::ffi::av_opt_find(
+                    class as *const _ as *mut c_void,
+                    c_name.as_ptr(),
+                    ptr::null(),
+                    0,
+                    (ffmpeg::ffi::AV_OPT_SEARCH_CHILDREN | ffmpeg::ffi::AV_OPT_SEARCH_FAKE_OBJ)
+                        as i32,
+                )
+                .is_null()
+            })
+        };
+        if !known {
+            bail!("unknown ffmpeg option '{}'", name);
+        }
+    }
+    Ok(())
+}
+
+/// Formats options for the log, sorted by name.
+pub fn describe(options: &HashMap<String, String>) -> String {
+    options
+        .iter()
+        .collect::<BTreeMap<_, _>>()
+        .into_iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn parses_entries() {
+        let options = parse(&[
+            "probesize = 32".to_string(),
+            "fflags=nobuffer+discardcorrupt".to_string(),
+        ])
+        .unwrap();
+        assert_eq!(options["probesize"], "32");
+        assert_eq!(options["fflags"], "nobuffer+discardcorrupt");
+    }
+
+    #[test]
+    fn rejects_entries_without_value() {
+        assert!(parse(&["low_delay".to_string()]).is_err());
+    }
+
+    #[test]
+    fn loads_scalars_from_yaml() {
+        let path = std::env::temp_dir().join(format!("go2_video_options_{}.yaml", process::id()));
+        fs::write(
+            &path,
+            "probesize: 32\nfflags: nobuffer\nreorder_queue_size: 0\nrefcounted_frames: true\n",
+        )
+        .unwrap();
+        let options = load(&path);
+        fs::remove_file(&path).unwrap();
+
+        let options = options.unwrap();
+        assert_eq!(options["probesize"], "32");
+        assert_eq!(options["fflags"], "nobuffer");
+        assert_eq!(options["reorder_queue_size"], "0");
+        assert_eq!(options["refcounted_frames"], "1");
+    }
+
+    #[test]
+    fn rejects_nested_yaml() {
+        let path =
+            std::env::temp_dir().join(format!("go2_video_options_nested_{}.yaml", process::id()));
+        fs::write(&path, "fflags: [nobuffer]\n").unwrap();
+        let options = load(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(options.is_err());
+    }
+
+    #[test]
+    fn missing_file_is_an_error() {
+        assert!(load(Path::new("/nonexistent/decoder_options.yaml")).is_err());
+    }
+
+    #[test]
+    fn validates_known_options() {
+        validate(&defaults()).unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_options() {
+        let mut options = defaults();
+        options.insert("probsize".to_string(), "32".to_string());
+        assert!(validate(&options).is_err());
+    }
+
+    #[test]
+    fn describes_sorted() {
+        let options = parse(&["b=2".to_string(), "a=1".to_string()]).unwrap();
+        assert_eq!(describe(&options), "a=1 b=2");
+    }
+}
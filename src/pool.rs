@@ -0,0 +1,87 @@
+//! Reusable frame buffers.
+//!
+//! Frames are copied out of ffmpeg into buffers from the pool, and the
+//! publisher hands each buffer back once the last user of the frame is done
+//! with it. A steady stream of equally sized frames then allocates nothing.
+
+use std::sync::{Arc, Mutex};
+
+/// Idle buffers kept around; more are only needed while frames pile up.
+const MAX_IDLE: usize = 4;
+
+#[derive(Clone, Default)]
+pub struct BufferPool {
+    idle: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl BufferPool {
+    pub fn new() -> BufferPool {
+        BufferPool::default()
+    }
+
+    /// Returns an empty buffer with room for at least `capacity` bytes.
+    pub fn get(&self, capacity: usize) -> Vec<u8> {
+        let idle = self.idle.lock().unwrap().pop();
+        match idle {
+            Some(mut buffer) if buffer.capacity() >= capacity => {
+                buffer.clear();
+                buffer
+            }
+            // A buffer that is too small, after the frame size changed, is dropped
+            _ => Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Hands a buffer back for reuse.
+    pub fn put(&self, buffer: Vec<u8>) {
+        if buffer.capacity() == 0 {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE {
+            idle.push(buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn returned_buffer_is_reused_empty() {
+        let pool = BufferPool::new();
+        let mut buffer = pool.get(16);
+        buffer.extend_from_slice(&[1; 16]);
+        let address = buffer.as_ptr();
+        pool.put(buffer);
+
+        let reused = pool.get(8);
+        assert_eq!(reused.as_ptr(), address);
+        assert!(reused.is_empty());
+    }
+
+    #[test]
+    fn too_small_buffer_is_replaced() {
+        let pool = BufferPool::new();
+        pool.put(Vec::with_capacity(8));
+        assert!(pool.get(16).capacity() >= 16);
+        assert!(pool.idle.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn keeps_at_most_max_idle_buffers() {
+        let pool = BufferPool::new();
+        for _ in 0..MAX_IDLE + 2 {
+            pool.put(Vec::with_capacity(8));
+        }
+        assert_eq!(pool.idle.lock().unwrap().len(), MAX_IDLE);
+    }
+
+    #[test]
+    fn ignores_buffers_without_capacity() {
+        let pool = BufferPool::new();
+        pool.put(Vec::new());
+        assert!(pool.idle.lock().unwrap().is_empty());
+    }
+}
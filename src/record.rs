@@ -0,0 +1,330 @@
+//! Recording of the encoded video into MP4 or Matroska files.
+//!
+//! Access units from the relay are muxed as they arrive, without re-encoding.
+//! Recordings are split into segments once a segment reaches the configured
+//! duration or size; the split happens at the next keyframe, so every segment
+//! starts with a picture that decodes on its own.
+
+use anyhow::{anyhow, bail, Result};
+use ffmpeg::{codec, format, Rational};
+use ffmpeg_next as ffmpeg;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::h264::{self, AccessUnit};
+use crate::stamp::RtpTime;
+
+const CLOCK_RATE: i32 = 90000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Container {
+    Mp4,
+    Matroska,
+}
+
+impl Container {
+    /// Parses the `record_format` parameter.
+    pub fn from_param(format: &str) -> Result<Container> {
+        match format {
+            "mp4" => Ok(Container::Mp4),
+            "mkv" => Ok(Container::Matroska),
+            _ => bail!("unknown record_format '{}', expected mp4 or mkv", format),
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Matroska => "mkv",
+        }
+    }
+
+    fn format_name(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Matroska => "matroska",
+        }
+    }
+}
+
+/// Where and how segments are written, read again for every new segment.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub directory: PathBuf,
+    /// File name without extension; `{datetime}`, `{stamp}` and `{segment}` are expanded
+    pub filename: String,
+    pub container: Container,
+    pub segment_duration: Option<Duration>,
+    /// Segment size in bytes
+    pub segment_size: Option<u64>,
+}
+
+impl Settings {
+    fn path(&self, segment: u32) -> PathBuf {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let name = self
+            .filename
+            .replace("{datetime}", &utc_datetime(now))
+            .replace("{stamp}", &now.to_string())
+            .replace("{segment}", &format!("{:03}", segment));
+        self.directory
+            .join(format!("{}.{}", name, self.container.extension()))
+    }
+}
+
+pub struct Recorder {
+    logger: String,
+    segment: Option<Segment>,
+    /// Segments written since the recorder was created
+    segments: u32,
+    rtp_time: RtpTime,
+    waiting_for_keyframe: bool,
+}
+
+impl Recorder {
+    pub fn new(logger: String) -> Recorder {
+        Recorder {
+            logger,
+            segment: None,
+            segments: 0,
+            rtp_time: RtpTime::default(),
+            waiting_for_keyframe: true,
+        }
+    }
+
+    /// Writes an access unit, starting a new segment first when one is due.
+    pub fn write(&mut self, access_unit: &AccessUnit, settings: &Settings) -> Result<()> {
+        let time = self
+            .rtp_time
+            .seconds(access_unit.rtp_timestamp, CLOCK_RATE as u32);
+
+        // Pictures after a loss reference what is missing, until the next keyframe
+        if self.waiting_for_keyframe || access_unit.after_loss {
+            if !access_unit.keyframe {
+                self.waiting_for_keyframe = true;
+                return Ok(());
+            }
+            self.waiting_for_keyframe = false;
+        }
+
+        if access_unit.keyframe
+            && self
+                .segment
+                .as_ref()
+                .is_some_and(|segment| segment.is_full(time, settings))
+        {
+            self.close()?;
+        }
+
+        let segment = match &mut self.segment {
+            Some(segment) => segment,
+            None => {
+                let path = settings.path(self.segments);
+                let segment = Segment::create(&path, settings.container, access_unit, time)?;
+                log_info!(&self.logger, "Recording to {}", path.display());
+                self.segments += 1;
+                self.segment.insert(segment)
+            }
+        };
+        segment.write(access_unit, time)
+    }
+
+    /// File the current segment is written to.
+    pub fn path(&self) -> Option<&Path> {
+        self.segment.as_ref().map(|segment| segment.path.as_path())
+    }
+
+    /// Skips everything up to the next keyframe, after access units were lost.
+    pub fn resync(&mut self) {
+        self.waiting_for_keyframe = true;
+    }
+
+    /// Finishes the current segment, if any; the next one starts at a keyframe.
+    pub fn stop(&mut self) -> Result<()> {
+        self.waiting_for_keyframe = true;
+        self.close()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        let Some(segment) = self.segment.take() else {
+            return Ok(());
+        };
+        let path = segment.path.clone();
+        segment.finish()?;
+        log_info!(&self.logger, "Finished {}", path.display());
+        Ok(())
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            log_error!(&self.logger, "Failed to finish recording: {:#}", e);
+        }
+    }
+}
+
+struct Segment {
+    path: PathBuf,
+    output: format::context::Output,
+    time_base: Rational,
+    /// Stream time of the first access unit, in seconds
+    start: f64,
+    last_pts: Option<i64>,
+    bytes: u64,
+}
+
+impl Segment {
+    /// Opens a new file; `keyframe` provides the parameter sets and picture size.
+    fn create(
+        path: &Path,
+        container: Container,
+        keyframe: &AccessUnit,
+        start: f64,
+    ) -> Result<Segment> {
+        let extradata = h264::parameter_sets(keyframe)
+            .ok_or_else(|| anyhow!("keyframe without SPS and PPS"))?;
+        let (width, height) = h264::nal_units(&extradata)
+            .first()
+            .and_then(|sps| h264::sps_dimensions(sps))
+            .ok_or_else(|| anyhow!("cannot read the picture size from the SPS"))?;
+
+        if let Some(directory) = path.parent() {
+            std::fs::create_dir_all(directory)?;
+        }
+        let mut output = format::output_as(path, container.format_name())?;
+
+        let mut parameters = codec::Parameters::new();
+        // ffmpeg-next has no setters for stream copy parameters
+        unsafe {
+            let p = parameters.as_mut_ptr();
+            (*p).codec_type = ffmpeg::ffi::AVMediaType::AVMEDIA_TYPE_VIDEO;
+            (*p).codec_id = ffmpeg::ffi::AVCodecID::AV_CODEC_ID_H264;
+            (*p).width = width as i32;
+            (*p).height = height as i32;
+            let padding = ffmpeg::ffi::AV_INPUT_BUFFER_PADDING_SIZE as usize;
+            let data = ffmpeg::ffi::av_mallocz(extradata.len() + padding) as *mut u8;
+            if data.is_null() {
+                bail!("out of memory");
+            }
+            ptr::copy_nonoverlapping(extradata.as_ptr(), data, extradata.len());
+            (*p).extradata = data;
+            (*p).extradata_size = extradata.len() as i32;
+        }
+
+        let mut stream = output.add_stream(ffmpeg::encoder::find(codec::Id::None))?;
+        stream.set_parameters(parameters);
+        stream.set_time_base(Rational::new(1, CLOCK_RATE));
+
+        let mut options = ffmpeg::Dictionary::new();
+        if container == Container::Mp4 {
+            // Fragmented, so a file cut short by a crash still plays
+            options.set("movflags", "frag_keyframe+empty_moov+default_base_moof");
+        }
+        output.write_header_with(options)?;
+        // The muxer may have picked a different time base
+        let time_base = output
+            .stream(0)
+            .ok_or_else(|| anyhow!("muxer dropped the video stream"))?
+            .time_base();
+
+        Ok(Segment {
+            path: path.to_path_buf(),
+            output,
+            time_base,
+            start,
+            last_pts: None,
+            bytes: 0,
+        })
+    }
+
+    fn is_full(&self, time: f64, settings: &Settings) -> bool {
+        let too_long = settings
+            .segment_duration
+            .is_some_and(|duration| time - self.start >= duration.as_secs_f64());
+        let too_big = settings.segment_size.is_some_and(|size| self.bytes >= size);
+        too_long || too_big
+    }
+
+    fn write(&mut self, access_unit: &AccessUnit, time: f64) -> Result<()> {
+        let mut pts = ((time - self.start) * CLOCK_RATE as f64).round() as i64;
+        // Muxers reject timestamps that do not increase, e.g. after a stream restart
+        if let Some(last) = self.last_pts {
+            pts = pts.max(last + 1);
+        }
+        self.last_pts = Some(pts);
+
+        let mut packet = ffmpeg::Packet::copy(&access_unit.data);
+        packet.set_stream(0);
+        packet.set_pts(Some(pts));
+        packet.set_dts(Some(pts));
+        if access_unit.keyframe {
+            packet.set_flags(codec::packet::Flags::KEY);
+        }
+        packet.rescale_ts(Rational::new(1, CLOCK_RATE), self.time_base);
+        packet.write_interleaved(&mut self.output)?;
+
+        self.bytes += access_unit.data.len() as u64;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.output.write_trailer()?;
+        Ok(())
+    }
+}
+
+/// Formats seconds since the epoch as `YYYYmmdd-HHMMSS` in UTC.
+fn utc_datetime(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let time = secs % 86400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utc_datetime_epoch() {
+        assert_eq!(utc_datetime(0), "19700101-000000");
+    }
+
+    #[test]
+    fn utc_datetime_leap_days() {
+        assert_eq!(utc_datetime(951_782_400), "20000229-000000");
+        assert_eq!(utc_datetime(1_709_251_199), "20240229-235959");
+        // 2100 is not a leap year
+        assert_eq!(utc_datetime(4_102_444_800), "21000101-000000");
+    }
+
+    #[test]
+    fn utc_datetime_time_of_day() {
+        assert_eq!(utc_datetime(1_700_000_000), "20231114-221320");
+    }
+}
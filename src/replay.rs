@@ -0,0 +1,115 @@
+//! Replay of a video file or ffmpeg URL in place of the robot's stream.
+//!
+//! Frames are decoded on a dedicated thread like the live stream, but paced by
+//! their presentation times so downstream nodes see a real-time feed.
+
+use anyhow::Result;
+use ffmpeg::util::frame;
+use ffmpeg_next as ffmpeg;
+use std::collections::HashMap;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+use crate::decode::{DecodedFrame, VideoInput};
+use crate::filter::{Filter, Output};
+use crate::latest;
+use crate::pool::BufferPool;
+
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// File path (MP4, MKV, raw H.264, ...) or any URL ffmpeg can open
+    pub url: String,
+    /// Start over when the end is reached
+    pub looping: bool,
+    /// Playback speed, 2.0 plays twice as fast
+    pub rate: f64,
+}
+
+/// Starts replaying on a new thread.
+///
+/// The thread ends, dropping `frames`, when the input ends without looping, fails,
+/// or nobody receives the frames anymore.
+pub fn spawn(
+    logger: String,
+    settings: Settings,
+    options: HashMap<String, String>,
+    output: watch::Receiver<Output>,
+    pool: BufferPool,
+    frames: latest::Sender<DecodedFrame>,
+) -> Result<thread::JoinHandle<()>> {
+    let handle = thread::Builder::new()
+        .name("go2_video_replay".to_string())
+        .spawn(move || {
+            if let Err(e) = replay(&logger, &settings, options, output, pool, &frames) {
+                log_warn!(&logger, "Replay stopped: {:#}", e);
+            }
+        })?;
+    Ok(handle)
+}
+
+fn replay(
+    logger: &str,
+    settings: &Settings,
+    options: HashMap<String, String>,
+    mut output: watch::Receiver<Output>,
+    pool: BufferPool,
+    frames: &latest::Sender<DecodedFrame>,
+) -> Result<()> {
+    // ffmpeg takes URLs wherever it takes paths
+    let source = Path::new(&settings.url);
+    let origin = Instant::now();
+    let current = *output.borrow_and_update();
+    let mut filter = Filter::new(current.encoding, &current.transform, pool.clone());
+    let mut frame = frame::Video::empty();
+    let mut sequence = 0;
+
+    loop {
+        log_info!(logger, "Replaying {}", settings.url);
+        let mut input = VideoInput::open(logger, source, &options)?;
+
+        let started = Instant::now();
+        let mut first_time = None;
+        while input.read_frame(&mut frame)?.is_some() {
+            if frames.is_closed() {
+                return Ok(());
+            }
+
+            // Pace by presentation time relative to the first frame of this pass
+            let time = input.time(&frame) / settings.rate;
+            let offset = *first_time.get_or_insert(time);
+            let elapsed = Duration::from_secs_f64((time - offset).max(0.0));
+            let due = started + elapsed;
+            if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                thread::sleep(wait);
+            }
+
+            filter.follow(&mut output);
+            let (data, width, height) = filter.run(&frame)?;
+            let now = Instant::now();
+            let replaced = frames.send(DecodedFrame {
+                sequence,
+                received_at: now,
+                decoded_at: now,
+                // Keeps growing across passes, so stream stamps stay monotonic
+                time: (started - origin + elapsed).as_secs_f64(),
+                source_size: (frame.width(), frame.height()),
+                width,
+                height,
+                encoding: filter.encoding(),
+                transform: filter.transform(),
+                data,
+            });
+            if let Some(replaced) = replaced {
+                pool.put(replaced.data);
+            }
+            sequence += 1;
+        }
+
+        if !settings.looping {
+            log_info!(logger, "Replay reached the end of {}", settings.url);
+            return Ok(());
+        }
+    }
+}
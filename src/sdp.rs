@@ -0,0 +1,138 @@
+//! Session descriptions for the local RTP streams.
+//!
+//! ffmpeg needs an SDP to know which port to listen on and how to depacketize what
+//! arrives there. The description is generated from the node parameters and the
+//! payload type observed on the wire, and handed to the decoder as a temp file.
+
+use anyhow::Result;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// First dynamic payload type (RFC 3551), assumed until the robot's video is seen.
+pub const DYNAMIC_PAYLOAD_TYPE: u8 = 96;
+
+/// H.264 as negotiated with the robot.
+#[derive(Clone, Debug)]
+pub struct H264 {
+    pub payload_type: u8,
+    pub clock_rate: u32,
+    /// Hex `profile_idc`, `profile-iop` and `level_idc`, as in RFC 6184.
+    pub profile_level_id: String,
+}
+
+/// Describes a single H.264 stream arriving on `port` of the loopback interface.
+pub fn video(port: u16, codec: &H264) -> String {
+    format!(
+        "v=0\r\n\
+         o=- 0 0 IN IP4 127.0.0.1\r\n\
+         s=go2_video\r\n\
+         c=IN IP4 127.0.0.1\r\n\
+         t=0 0\r\n\
+         m=video {port} RTP/AVP {pt}\r\n\
+         a=rtpmap:{pt} H264/{clock_rate}\r\n\
+         a=fmtp:{pt} packetization-mode=1;profile-level-id={profile}\r\n",
+        port = port,
+        pt = codec.payload_type,
+        clock_rate = codec.clock_rate,
+        profile = codec.profile_level_id,
+    )
+}
+
+/// Opus as negotiated with the robot.
+#[derive(Clone, Debug)]
+pub struct Opus {
+    pub payload_type: u8,
+    pub channels: u8,
+}
+
+/// Describes a single Opus stream arriving on `port` of the loopback interface.
+pub fn audio(port: u16, codec: &Opus) -> String {
+    format!(
+        "v=0\r\n\
+         o=- 0 0 IN IP4 127.0.0.1\r\n\
+         s=go2_audio\r\n\
+         c=IN IP4 127.0.0.1\r\n\
+         t=0 0\r\n\
+         m=audio {port} RTP/AVP {pt}\r\n\
+         a=rtpmap:{pt} opus/48000/{channels}\r\n",
+        port = port,
+        pt = codec.payload_type,
+        channels = codec.channels,
+    )
+}
+
+/// An SDP written to the temp directory, removed again when dropped.
+#[derive(Debug)]
+pub struct SdpFile {
+    path: PathBuf,
+}
+
+impl SdpFile {
+    pub fn create(name: &str, sdp: &str) -> Result<SdpFile> {
+        // Numbered, an SDP written again must not share the path of one still in use
+        static SEQUENCE: AtomicU32 = AtomicU32::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "go2_video_{}_{}_{}.sdp",
+            process::id(),
+            name,
+            SEQUENCE.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&path, sdp)?;
+        Ok(SdpFile { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SdpFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn video_description() {
+        let codec = H264 {
+            payload_type: 97,
+            clock_rate: 90000,
+            profile_level_id: "42e01f".to_string(),
+        };
+        let sdp = video(5004, &codec);
+        assert!(sdp.starts_with("v=0\r\n"));
+        assert!(sdp.contains("m=video 5004 RTP/AVP 97\r\n"));
+        assert!(sdp.contains("a=rtpmap:97 H264/90000\r\n"));
+        assert!(sdp.contains("a=fmtp:97 packetization-mode=1;profile-level-id=42e01f\r\n"));
+        assert!(sdp.lines().all(|line| !line.starts_with(' ')));
+    }
+
+    #[test]
+    fn audio_description() {
+        let codec = Opus {
+            payload_type: 111,
+            channels: 2,
+        };
+        let sdp = audio(5006, &codec);
+        assert!(sdp.contains("m=audio 5006 RTP/AVP 111\r\n"));
+        assert!(sdp.contains("a=rtpmap:111 opus/48000/2\r\n"));
+    }
+
+    #[test]
+    fn files_are_distinct_and_removed() {
+        let first = SdpFile::create("test", "v=0\r\n").unwrap();
+        let second = SdpFile::create("test", "v=0\r\n").unwrap();
+        assert_ne!(first.path(), second.path());
+        assert_eq!(fs::read_to_string(first.path()).unwrap(), "v=0\r\n");
+
+        let path = first.path().to_path_buf();
+        drop(first);
+        assert!(!path.exists());
+    }
+}
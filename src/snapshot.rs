@@ -0,0 +1,35 @@
+//! Saving single frames for the snapshot service.
+
+use anyhow::{anyhow, Result};
+use r2r::builtin_interfaces::msg::Time;
+use r2r::sensor_msgs::msg::Image;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::compressed::{self, Format};
+
+/// Expands `{stamp}` in the `snapshot_path` template to the image stamp.
+pub fn path(template: &str, stamp: &Time) -> PathBuf {
+    PathBuf::from(template.replace("{stamp}", &format!("{}_{:09}", stamp.sec, stamp.nanosec)))
+}
+
+/// Writes `image` to `path`, as JPEG or PNG depending on the extension.
+pub fn save(image: &Image, path: &Path, jpeg_quality: i32, png_level: i32) -> Result<()> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    let format = match extension.as_deref() {
+        Some("jpg") | Some("jpeg") => Format::from_params("jpeg", jpeg_quality, png_level)?,
+        Some("png") => Format::from_params("png", jpeg_quality, png_level)?,
+        _ => None,
+    }
+    .ok_or_else(|| anyhow!("{} is not a .jpg or .png file", path.display()))?;
+
+    let encoded = compressed::encode(image, format)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, encoded.data)?;
+    Ok(())
+}
@@ -0,0 +1,289 @@
+//! Decoded video from the robot as an async stream.
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use futures::StreamExt;
+//! use ros2_go2_video::Go2VideoSource;
+//!
+//! let mut video = Go2VideoSource::new("192.168.123.161")
+//!     .robot_token("...")
+//!     .start()
+//!     .await?;
+//! let mut frames = video.frames().await?;
+//! while let Some(frame) = frames.next().await {
+//!     println!("{}x{} at {:.3}s", frame.width, frame.height, frame.time);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::Result;
+use futures::Stream;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::watch;
+
+use crate::decode::{self, DecodedFrame};
+use crate::encoding::Encoding;
+use crate::filter::Output;
+use crate::latest;
+use crate::options;
+use crate::pool::BufferPool;
+use crate::sdp::{self, SdpFile};
+use crate::transform::Transform;
+use crate::webrtc::{self, Session, Traffic};
+
+/// Builds a connection to the robot's video.
+#[derive(Clone, Debug)]
+pub struct Go2VideoSource {
+    config: webrtc::Config,
+    connect_timeout: Duration,
+    h264_profile_level_id: String,
+    decoder_options: HashMap<String, String>,
+    encoding: Encoding,
+    transform: Transform,
+    traffic: Arc<Traffic>,
+    pool: BufferPool,
+    /// Written ahead of the session, with the payload type it assumes
+    sdp: Option<(u8, Arc<SdpFile>)>,
+    logger: String,
+}
+
+impl Go2VideoSource {
+    pub fn new(robot_ip: impl Into<String>) -> Go2VideoSource {
+        Go2VideoSource {
+            config: webrtc::Config {
+                robot_ip: robot_ip.into(),
+                robot_token: String::new(),
+                video_port: 4002,
+                audio_port: 4000,
+                debug_webrtc: false,
+                stall_timeout: Duration::from_secs(5),
+            },
+            connect_timeout: Duration::from_secs(10),
+            h264_profile_level_id: "42e01f".to_string(),
+            decoder_options: options::defaults(),
+            encoding: Encoding::Rgb8,
+            transform: Transform::default(),
+            traffic: Arc::default(),
+            pool: BufferPool::new(),
+            sdp: None,
+            logger: "go2_video".to_string(),
+        }
+    }
+
+    pub fn robot_ip(mut self, robot_ip: impl Into<String>) -> Self {
+        self.config.robot_ip = robot_ip.into();
+        self
+    }
+
+    pub fn robot_token(mut self, token: impl Into<String>) -> Self {
+        self.config.robot_token = token.into();
+        self
+    }
+
+    /// Local port the decoder reads video RTP from.
+    pub fn video_port(mut self, port: u16) -> Self {
+        self.config.video_port = port;
+        self
+    }
+
+    /// Local port audio RTP is forwarded to.
+    pub fn audio_port(mut self, port: u16) -> Self {
+        self.config.audio_port = port;
+        self
+    }
+
+    pub fn debug_webrtc(mut self, debug: bool) -> Self {
+        self.config.debug_webrtc = debug;
+        self
+    }
+
+    /// How long video may stop before the session is considered failed.
+    pub fn stall_timeout(mut self, timeout: Duration) -> Self {
+        self.config.stall_timeout = timeout;
+        self
+    }
+
+    /// How long to wait for the first video from the robot.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// H.264 profile-level-id announced to the decoder.
+    pub fn h264_profile_level_id(mut self, profile_level_id: impl Into<String>) -> Self {
+        self.h264_profile_level_id = profile_level_id.into();
+        self
+    }
+
+    /// Replaces all ffmpeg options, see [`options::defaults`].
+    pub fn decoder_options(mut self, options: HashMap<String, String>) -> Self {
+        self.decoder_options = options;
+        self
+    }
+
+    /// Sets a single ffmpeg option on top of the current ones.
+    pub fn decoder_option(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.decoder_options.insert(name.into(), value.into());
+        self
+    }
+
+    pub fn encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn transform(mut self, transform: Transform) -> Self {
+        self.transform = transform;
+        self
+    }
+
+    /// Counts the video passing through the session into `traffic`.
+    pub fn traffic(mut self, traffic: Arc<Traffic>) -> Self {
+        self.traffic = traffic;
+        self
+    }
+
+    /// Takes frame buffers from `pool`; hand them back with [`BufferPool::put`]
+    /// to avoid an allocation per frame.
+    pub fn buffer_pool(mut self, pool: BufferPool) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    /// Writes the video SDP now instead of once video arrives, assuming the robot
+    /// sends `payload_type`; it is written again if the robot picks another one.
+    ///
+    /// Call it after the ports and profile are set, it does not follow later changes.
+    pub fn prepare_sdp(mut self, payload_type: u8) -> Result<Self> {
+        self.sdp = Some((payload_type, Arc::new(self.write_sdp(payload_type)?)));
+        Ok(self)
+    }
+
+    fn write_sdp(&self, payload_type: u8) -> Result<SdpFile> {
+        let codec = sdp::H264 {
+            payload_type,
+            clock_rate: 90000,
+            profile_level_id: self.h264_profile_level_id.clone(),
+        };
+        SdpFile::create("video", &sdp::video(self.config.video_port, &codec))
+    }
+
+    /// Logger name for messages from the decoder.
+    pub fn logger(mut self, logger: impl Into<String>) -> Self {
+        self.logger = logger.into();
+        self
+    }
+
+    /// Starts a WebRTC session with the robot.
+    pub async fn start(self) -> Result<Go2Video> {
+        options::validate(&self.decoder_options)?;
+        log_info!(
+            &self.logger,
+            "Decoder options: {}",
+            options::describe(&self.decoder_options)
+        );
+        let session = Session::start(self.config.clone(), self.traffic.clone()).await?;
+        let output = watch::channel(Output {
+            encoding: self.encoding,
+            transform: self.transform,
+        })
+        .0;
+        Ok(Go2Video {
+            source: self,
+            session,
+            sdp: None,
+            output,
+        })
+    }
+}
+
+/// A running session with the robot; dropping it ends the session and its frame streams.
+pub struct Go2Video {
+    source: Go2VideoSource,
+    session: Session,
+    sdp: Option<Arc<SdpFile>>,
+    output: watch::Sender<Output>,
+}
+
+impl Go2Video {
+    /// The session, for its state, the encoded video and the audio.
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    pub fn audio_port(&self) -> u16 {
+        self.source.config.audio_port
+    }
+
+    /// Encoding and transform of decoded frames; a new value sent here switches
+    /// running decoders at their next frame, without reconnecting.
+    pub fn output(&self) -> &watch::Sender<Output> {
+        &self.output
+    }
+
+    /// Waits until video is flowing to the decoder port, up to the connect timeout.
+    pub async fn wait_for_video(&mut self) -> Result<()> {
+        if self.sdp.is_some() {
+            return Ok(());
+        }
+        let payload_type = self
+            .session
+            .wait_for_video(self.source.connect_timeout)
+            .await?;
+        let sdp = match &self.source.sdp {
+            Some((prepared, sdp)) if *prepared == payload_type => sdp.clone(),
+            _ => Arc::new(self.source.write_sdp(payload_type)?),
+        };
+        self.sdp = Some(sdp);
+        Ok(())
+    }
+
+    /// Starts a decoder and returns its frames, once video is flowing.
+    ///
+    /// The stream ends when the session ends or [`Session::end_video_stream`] is
+    /// called; a decoder started after that picks up at the next keyframe.
+    pub async fn frames(&mut self) -> Result<Frames> {
+        self.wait_for_video().await?;
+        let (frames_tx, frames_rx) = latest::channel();
+        decode::spawn(
+            self.source.logger.clone(),
+            self.sdp.clone().unwrap(),
+            self.source.decoder_options.clone(),
+            self.output.subscribe(),
+            self.source.pool.clone(),
+            frames_tx,
+        )?;
+        Ok(Frames::from(frames_rx))
+    }
+}
+
+/// Decoded frames, the latest one wins when the consumer falls behind.
+pub struct Frames {
+    receiver: latest::Receiver<DecodedFrame>,
+}
+
+impl Frames {
+    /// Number of frames replaced before they were received.
+    pub fn dropped(&self) -> u64 {
+        self.receiver.dropped()
+    }
+}
+
+impl From<latest::Receiver<DecodedFrame>> for Frames {
+    fn from(receiver: latest::Receiver<DecodedFrame>) -> Frames {
+        Frames { receiver }
+    }
+}
+
+impl Stream for Frames {
+    type Item = DecodedFrame;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<DecodedFrame>> {
+        self.receiver.poll_recv(cx)
+    }
+}
@@ -0,0 +1,150 @@
+//! Mapping stream timestamps to ROS time.
+//!
+//! go2webrtc-rs does not forward RTCP sender reports, so there is no wall clock
+//! reference for the stream. The first frame is aligned with its arrival time
+//! instead, and the offset is then adjusted from the arrival times of later frames:
+//! a frame arriving earlier than predicted moves the anchor back immediately, a
+//! consistently later one moves it forward by `drift_gain` per frame. This keeps the
+//! stamps as smooth as the stream clock while following drift between the robot's
+//! clock and ours.
+
+use std::time::Duration;
+
+/// Offsets further off than this are treated as a stream discontinuity.
+const MAX_OFFSET_JUMP: f64 = 1.0;
+
+pub struct StreamClock {
+    drift_gain: f64,
+    /// ROS time minus stream time, in seconds
+    offset: Option<f64>,
+}
+
+impl StreamClock {
+    pub fn new(drift_gain: f64) -> StreamClock {
+        StreamClock {
+            drift_gain: drift_gain.clamp(0.0, 1.0),
+            offset: None,
+        }
+    }
+
+    /// Changes the drift gain from the next frame on, keeping the current offset.
+    pub fn set_drift_gain(&mut self, drift_gain: f64) {
+        self.drift_gain = drift_gain.clamp(0.0, 1.0);
+    }
+
+    /// Returns the ROS time of a frame with the given stream time received at `now`.
+    pub fn stamp(&mut self, stream_time: f64, now: Duration) -> Duration {
+        let arrival_offset = now.as_secs_f64() - stream_time;
+        let offset = match self.offset {
+            Some(offset) if (arrival_offset - offset).abs() > MAX_OFFSET_JUMP => arrival_offset,
+            Some(offset) if arrival_offset < offset => arrival_offset,
+            Some(offset) => offset + (arrival_offset - offset) * self.drift_gain,
+            None => arrival_offset,
+        };
+        self.offset = Some(offset);
+
+        Duration::try_from_secs_f64(stream_time + offset).unwrap_or(now)
+    }
+}
+
+/// Extends 32-bit RTP timestamps across wrap-arounds.
+#[derive(Default)]
+pub struct RtpTime {
+    last: Option<u32>,
+    extended: i64,
+}
+
+impl RtpTime {
+    /// Returns the stream time in seconds for an RTP timestamp.
+    pub fn seconds(&mut self, timestamp: u32, clock_rate: u32) -> f64 {
+        if let Some(last) = self.last {
+            self.extended += timestamp.wrapping_sub(last) as i32 as i64;
+        }
+        self.last = Some(timestamp);
+        self.extended as f64 / clock_rate as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(stamp: Duration) -> f64 {
+        stamp.as_secs_f64()
+    }
+
+    #[test]
+    fn first_frame_is_stamped_with_its_arrival() {
+        let mut clock = StreamClock::new(0.01);
+        let stamp = clock.stamp(5.0, Duration::from_secs(100));
+        assert!((secs(stamp) - 100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn late_arrivals_do_not_jitter_the_stamps() {
+        let mut clock = StreamClock::new(0.0);
+        clock.stamp(0.0, Duration::from_secs(100));
+        // Arrives 50ms late, the stream clock still decides
+        let stamp = clock.stamp(1.0, Duration::from_secs_f64(101.05));
+        assert!((secs(stamp) - 101.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn early_arrival_moves_the_anchor_back() {
+        let mut clock = StreamClock::new(0.0);
+        clock.stamp(0.0, Duration::from_secs_f64(100.2));
+        let stamp = clock.stamp(1.0, Duration::from_secs(101));
+        assert!((secs(stamp) - 101.0).abs() < 1e-9);
+        let stamp = clock.stamp(2.0, Duration::from_secs_f64(102.1));
+        assert!((secs(stamp) - 102.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn follows_drift_by_the_gain() {
+        let mut clock = StreamClock::new(0.5);
+        clock.stamp(0.0, Duration::from_secs(100));
+        let stamp = clock.stamp(1.0, Duration::from_secs_f64(101.1));
+        assert!((secs(stamp) - 101.05).abs() < 1e-9);
+    }
+
+    #[test]
+    fn changed_gain_keeps_the_offset() {
+        let mut clock = StreamClock::new(0.0);
+        clock.stamp(0.0, Duration::from_secs(100));
+        clock.set_drift_gain(0.5);
+        let stamp = clock.stamp(1.0, Duration::from_secs_f64(101.1));
+        assert!((secs(stamp) - 101.05).abs() < 1e-9);
+    }
+
+    #[test]
+    fn discontinuity_resets_the_offset() {
+        let mut clock = StreamClock::new(0.0);
+        clock.stamp(0.0, Duration::from_secs(100));
+        // The stream restarted at zero
+        let stamp = clock.stamp(0.0, Duration::from_secs(130));
+        assert!((secs(stamp) - 130.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rtp_time_counts_from_the_first_timestamp() {
+        let mut time = RtpTime::default();
+        assert_eq!(time.seconds(123_456, 90000), 0.0);
+        assert_eq!(time.seconds(123_456 + 90000, 90000), 1.0);
+    }
+
+    #[test]
+    fn rtp_time_wraps_around() {
+        let mut time = RtpTime::default();
+        time.seconds(u32::MAX - 44_999, 90000);
+        assert_eq!(time.seconds(45_000, 90000), 1.0);
+        assert_eq!(time.seconds(135_000, 90000), 2.0);
+    }
+
+    #[test]
+    fn rtp_time_goes_back_for_reordered_packets() {
+        let mut time = RtpTime::default();
+        time.seconds(10, 90000);
+        time.seconds(90010, 90000);
+        assert_eq!(time.seconds(45010, 90000), 0.5);
+    }
+}
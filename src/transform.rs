@@ -0,0 +1,388 @@
+//! Cropping, rotation and resizing of the published images.
+//!
+//! The geometry is applied by the ffmpeg filter graph in the order crop,
+//! rotate, flip, scale; the calibration is adjusted the same way so that
+//! camera_info keeps matching the images.
+
+use anyhow::{bail, Result};
+#[cfg(feature = "ros")]
+use r2r::sensor_msgs::msg::CameraInfo;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Rotation {
+    #[default]
+    None,
+    /// Clockwise
+    Cw90,
+    Cw180,
+    Cw270,
+}
+
+impl Rotation {
+    /// Parses the `rotation` parameter, degrees clockwise.
+    pub fn from_param(degrees: i32) -> Result<Rotation> {
+        match degrees {
+            0 => Ok(Rotation::None),
+            90 => Ok(Rotation::Cw90),
+            180 => Ok(Rotation::Cw180),
+            270 => Ok(Rotation::Cw270),
+            _ => bail!("rotation must be 0, 90, 180 or 270, got {}", degrees),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Flip {
+    #[default]
+    None,
+    Horizontal,
+    Vertical,
+}
+
+impl Flip {
+    /// Parses the `flip` parameter.
+    pub fn from_param(flip: &str) -> Result<Flip> {
+        match flip {
+            "none" | "" => Ok(Flip::None),
+            "horizontal" => Ok(Flip::Horizontal),
+            "vertical" => Ok(Flip::Vertical),
+            _ => bail!(
+                "unknown flip '{}', expected none, horizontal or vertical",
+                flip
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// The default leaves images as they are.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Transform {
+    pub crop: Option<Crop>,
+    pub rotation: Rotation,
+    pub flip: Flip,
+    /// Output width, 0 keeps the width (or follows the aspect ratio)
+    pub width: u32,
+    /// Output height, 0 keeps the height (or follows the aspect ratio)
+    pub height: u32,
+    /// Fit into width x height without distorting the picture
+    pub keep_aspect: bool,
+}
+
+impl Transform {
+    /// Returns true if images are published as they are decoded.
+    pub fn is_identity(&self) -> bool {
+        self.crop.is_none()
+            && self.rotation == Rotation::None
+            && self.flip == Flip::None
+            && self.width == 0
+            && self.height == 0
+    }
+
+    /// ffmpeg filters that apply the transform, empty if there is nothing to do.
+    pub fn filters(&self) -> Vec<String> {
+        let mut filters = Vec::new();
+        if let Some(crop) = self.crop {
+            filters.push(format!(
+                "crop={}:{}:{}:{}",
+                crop.width, crop.height, crop.x, crop.y
+            ));
+        }
+        match self.rotation {
+            Rotation::None => {}
+            Rotation::Cw90 => filters.push("transpose=clock".to_string()),
+            Rotation::Cw180 => filters.push("hflip,vflip".to_string()),
+            Rotation::Cw270 => filters.push("transpose=cclock".to_string()),
+        }
+        match self.flip {
+            Flip::None => {}
+            Flip::Horizontal => filters.push("hflip".to_string()),
+            Flip::Vertical => filters.push("vflip".to_string()),
+        }
+        if self.width > 0 || self.height > 0 {
+            let scale = if !self.keep_aspect {
+                // 0 keeps the input size in that direction
+                format!("scale={}:{}", self.width, self.height)
+            } else if self.width > 0 && self.height > 0 {
+                format!(
+                    "scale={}:{}:force_original_aspect_ratio=decrease",
+                    self.width, self.height
+                )
+            } else {
+                // -2 follows the aspect ratio, rounded to an even size
+                let size = |value: u32| if value > 0 { value as i64 } else { -2 };
+                format!("scale={}:{}", size(self.width), size(self.height))
+            };
+            filters.push(scale);
+        }
+        filters
+    }
+
+    /// Adjusts a calibration for the source size to images of `width` x `height`
+    /// that came out of the filter graph.
+    #[cfg(feature = "ros")]
+    pub fn camera_info(&self, info: &CameraInfo, width: u32, height: u32) -> CameraInfo {
+        let mut info = info.clone();
+        let (mut w, mut h) = (info.width as f64, info.height as f64);
+
+        if let Some(crop) = self.crop {
+            let (x, y) = (crop.x as f64, crop.y as f64);
+            map_intrinsics(&mut info, |[fx, fy, cx, cy]| [fx, fy, cx - x, cy - y]);
+            (w, h) = (crop.width as f64, crop.height as f64);
+        }
+
+        // Pixel centers are at integer coordinates, so a mirrored cx is w - 1 - cx
+        match self.rotation {
+            Rotation::None => {}
+            Rotation::Cw90 => {
+                map_intrinsics(&mut info, |[fx, fy, cx, cy]| [fy, fx, h - 1.0 - cy, cx]);
+                map_tangential(&mut info, |[p1, p2]| [p2, -p1]);
+                (w, h) = (h, w);
+            }
+            Rotation::Cw180 => {
+                map_intrinsics(&mut info, |[fx, fy, cx, cy]| {
+                    [fx, fy, w - 1.0 - cx, h - 1.0 - cy]
+                });
+                map_tangential(&mut info, |[p1, p2]| [-p1, -p2]);
+            }
+            Rotation::Cw270 => {
+                map_intrinsics(&mut info, |[fx, fy, cx, cy]| [fy, fx, cy, w - 1.0 - cx]);
+                map_tangential(&mut info, |[p1, p2]| [-p2, p1]);
+                (w, h) = (h, w);
+            }
+        }
+
+        match self.flip {
+            Flip::None => {}
+            Flip::Horizontal => {
+                map_intrinsics(&mut info, |[fx, fy, cx, cy]| [fx, fy, w - 1.0 - cx, cy]);
+                map_tangential(&mut info, |[p1, p2]| [p1, -p2]);
+            }
+            Flip::Vertical => {
+                map_intrinsics(&mut info, |[fx, fy, cx, cy]| [fx, fy, cx, h - 1.0 - cy]);
+                map_tangential(&mut info, |[p1, p2]| [-p1, p2]);
+            }
+        }
+
+        // Whatever size the scaler settled on
+        let (sx, sy) = (width as f64 / w, height as f64 / h);
+        map_intrinsics(&mut info, |[fx, fy, cx, cy]| {
+            [
+                fx * sx,
+                fy * sy,
+                (cx + 0.5) * sx - 0.5,
+                (cy + 0.5) * sy - 0.5,
+            ]
+        });
+
+        info.width = width;
+        info.height = height;
+        // The region of interest refers to the source image
+        info.roi = Default::default();
+        info
+    }
+}
+
+/// Applies `f` to fx, fy, cx and cy of K and P; the baseline terms of P are kept.
+#[cfg(feature = "ros")]
+fn map_intrinsics(info: &mut CameraInfo, f: impl Fn([f64; 4]) -> [f64; 4]) {
+    for (matrix, [fx, fy, cx, cy]) in [(&mut info.k, [0, 4, 2, 5]), (&mut info.p, [0, 5, 2, 6])] {
+        if matrix.len() <= cy {
+            continue;
+        }
+        let mapped = f([matrix[fx], matrix[fy], matrix[cx], matrix[cy]]);
+        (matrix[fx], matrix[fy], matrix[cx], matrix[cy]) =
+            (mapped[0], mapped[1], mapped[2], mapped[3]);
+    }
+}
+
+/// Applies `f` to the tangential distortion coefficients p1 and p2.
+///
+/// Radial terms do not depend on the orientation and stay as they are.
+#[cfg(feature = "ros")]
+fn map_tangential(info: &mut CameraInfo, f: impl Fn([f64; 2]) -> [f64; 2]) {
+    let tangential = matches!(
+        info.distortion_model.as_str(),
+        "plumb_bob" | "rational_polynomial"
+    );
+    if tangential && info.d.len() >= 4 {
+        let [p1, p2] = f([info.d[2], info.d[3]]);
+        (info.d[2], info.d[3]) = (p1, p2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_is_identity() {
+        assert!(Transform::default().is_identity());
+        assert!(Transform::default().filters().is_empty());
+    }
+
+    #[test]
+    fn filters_in_order() {
+        let transform = Transform {
+            crop: Some(Crop {
+                x: 10,
+                y: 20,
+                width: 640,
+                height: 360,
+            }),
+            rotation: Rotation::Cw90,
+            flip: Flip::Horizontal,
+            width: 320,
+            height: 320,
+            keep_aspect: true,
+        };
+        assert!(!transform.is_identity());
+        assert_eq!(
+            transform.filters(),
+            [
+                "crop=640:360:10:20",
+                "transpose=clock",
+                "hflip",
+                "scale=320:320:force_original_aspect_ratio=decrease",
+            ]
+        );
+    }
+
+    #[test]
+    fn scale_follows_the_aspect_ratio() {
+        let transform = Transform {
+            width: 640,
+            keep_aspect: true,
+            ..Transform::default()
+        };
+        assert_eq!(transform.filters(), ["scale=640:-2"]);
+
+        let transform = Transform {
+            height: 480,
+            ..Transform::default()
+        };
+        assert_eq!(transform.filters(), ["scale=0:480"]);
+    }
+
+    #[test]
+    fn parses_params() {
+        assert_eq!(Rotation::from_param(270).unwrap(), Rotation::Cw270);
+        assert!(Rotation::from_param(45).is_err());
+        assert_eq!(Flip::from_param("").unwrap(), Flip::None);
+        assert!(Flip::from_param("diagonal").is_err());
+    }
+
+    #[cfg(feature = "ros")]
+    mod camera_info {
+        use super::*;
+
+        /// 640x480 with fx, fy, cx, cy = 500, 510, 300, 200.
+        fn calibration() -> CameraInfo {
+            let k = vec![500.0, 0.0, 300.0, 0.0, 510.0, 200.0, 0.0, 0.0, 1.0];
+            let p = vec![
+                500.0, 0.0, 300.0, 0.0, 0.0, 510.0, 200.0, 0.0, 0.0, 0.0, 1.0, 0.0,
+            ];
+            CameraInfo {
+                width: 640,
+                height: 480,
+                distortion_model: "plumb_bob".to_string(),
+                d: vec![0.1, 0.01, 0.001, 0.002, 0.0],
+                k,
+                p,
+                ..Default::default()
+            }
+        }
+
+        /// fx, fy, cx and cy of K, checked against P.
+        fn intrinsics(info: &CameraInfo) -> [f64; 4] {
+            let k = [info.k[0], info.k[4], info.k[2], info.k[5]];
+            assert_eq!(k, [info.p[0], info.p[5], info.p[2], info.p[6]]);
+            k
+        }
+
+        fn assert_close(actual: [f64; 4], expected: [f64; 4]) {
+            for (a, e) in actual.iter().zip(expected) {
+                assert!((a - e).abs() < 1e-9, "{:?} != {:?}", actual, expected);
+            }
+        }
+
+        #[test]
+        fn identity_keeps_the_calibration() {
+            let info = Transform::default().camera_info(&calibration(), 640, 480);
+            assert_close(intrinsics(&info), [500.0, 510.0, 300.0, 200.0]);
+            assert_eq!(info.d, calibration().d);
+        }
+
+        #[test]
+        fn crop_moves_the_principal_point() {
+            let transform = Transform {
+                crop: Some(Crop {
+                    x: 100,
+                    y: 50,
+                    width: 320,
+                    height: 240,
+                }),
+                ..Transform::default()
+            };
+            let info = transform.camera_info(&calibration(), 320, 240);
+            assert_close(intrinsics(&info), [500.0, 510.0, 200.0, 150.0]);
+            assert_eq!((info.width, info.height), (320, 240));
+        }
+
+        #[test]
+        fn rotation_swaps_the_axes() {
+            let transform = Transform {
+                rotation: Rotation::Cw90,
+                ..Transform::default()
+            };
+            let info = transform.camera_info(&calibration(), 480, 640);
+            assert_close(intrinsics(&info), [510.0, 500.0, 279.0, 300.0]);
+            assert_eq!(info.d[2..4], [0.002, -0.001]);
+        }
+
+        #[test]
+        fn opposite_rotations_cancel() {
+            let rotate = |rotation, info: &CameraInfo| {
+                let transform = Transform {
+                    rotation,
+                    ..Transform::default()
+                };
+                transform.camera_info(info, info.height, info.width)
+            };
+            let info = rotate(Rotation::Cw270, &rotate(Rotation::Cw90, &calibration()));
+            assert_close(intrinsics(&info), intrinsics(&calibration()));
+            assert_eq!(info.d, calibration().d);
+        }
+
+        #[test]
+        fn flips_mirror_the_principal_point() {
+            let transform = Transform {
+                rotation: Rotation::Cw180,
+                flip: Flip::Vertical,
+                ..Transform::default()
+            };
+            // Upside down and back is a horizontal mirror
+            let info = transform.camera_info(&calibration(), 640, 480);
+            assert_close(intrinsics(&info), [500.0, 510.0, 339.0, 200.0]);
+            assert_eq!(info.d[2..4], [0.001, -0.002]);
+        }
+
+        #[test]
+        fn scaling_keeps_pixel_centers() {
+            let transform = Transform {
+                width: 320,
+                height: 240,
+                ..Transform::default()
+            };
+            let info = transform.camera_info(&calibration(), 320, 240);
+            assert_close(intrinsics(&info), [250.0, 255.0, 149.75, 99.75]);
+        }
+    }
+}
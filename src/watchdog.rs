@@ -0,0 +1,197 @@
+//! Detection of a frozen video stream.
+//!
+//! The relay notices when RTP stops, but not when packets keep coming and the
+//! decoder produces nothing, or keeps producing the same picture. The watchdog
+//! tracks the time since the last frame that differed from its predecessor and
+//! escalates step by step while the stream stays frozen.
+//!
+//! There is no step that asks the robot for a keyframe: go2webrtc-rs keeps the
+//! peer connection to itself and cannot send RTCP feedback such as a PLI. A
+//! resync restarts the decoder, which then waits for the next keyframe the
+//! robot sends on its own.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Thresholds {
+    /// Frozen this long: log a warning
+    pub warn: Duration,
+    /// Frozen this long: restart the decoder so it resyncs on the robot's next keyframe
+    pub resync: Duration,
+    /// Frozen this long: restart the WebRTC session
+    pub restart: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Action {
+    None,
+    Warn,
+    Resync,
+    Restart,
+}
+
+pub struct Watchdog {
+    thresholds: Thresholds,
+    last_new_frame: Instant,
+    last_fingerprint: Option<u64>,
+    /// Highest action taken since the last new frame, each one is taken once
+    escalation: Action,
+    resync_pending: bool,
+}
+
+impl Watchdog {
+    pub fn new(thresholds: Thresholds) -> Watchdog {
+        Watchdog {
+            thresholds,
+            last_new_frame: Instant::now(),
+            last_fingerprint: None,
+            escalation: Action::None,
+            resync_pending: false,
+        }
+    }
+
+    /// Records a decoded frame; returns true if the stream recovered from a freeze.
+    pub fn frame(&mut self, data: &[u8]) -> bool {
+        let fingerprint = fingerprint(data);
+        if self.last_fingerprint == Some(fingerprint) {
+            return false;
+        }
+        self.last_fingerprint = Some(fingerprint);
+        self.last_new_frame = Instant::now();
+
+        let recovered = self.escalation != Action::None;
+        self.escalation = Action::None;
+        recovered
+    }
+
+    /// Returns the next escalation step that is due, if any.
+    ///
+    /// Nothing is due before the first frame, while the decoder is still probing the stream.
+    pub fn check(&mut self) -> Action {
+        if self.last_fingerprint.is_none() {
+            return Action::None;
+        }
+        let frozen = self.frozen_for();
+        let due = if frozen >= self.thresholds.restart {
+            Action::Restart
+        } else if frozen >= self.thresholds.resync {
+            Action::Resync
+        } else if frozen >= self.thresholds.warn {
+            Action::Warn
+        } else {
+            Action::None
+        };
+
+        if due <= self.escalation {
+            return Action::None;
+        }
+        self.escalation = due;
+        if due == Action::Resync {
+            self.resync_pending = true;
+        }
+        due
+    }
+
+    /// Time since the last frame that differed from the one before.
+    pub fn frozen_for(&self) -> Duration {
+        self.last_new_frame.elapsed()
+    }
+
+    /// Returns whether a decoder restart was requested, and clears the request.
+    pub fn take_resync(&mut self) -> bool {
+        std::mem::take(&mut self.resync_pending)
+    }
+}
+
+/// Hashes every byte, so a change in a small part of an otherwise still picture counts.
+fn fingerprint(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(data);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn thresholds(warn: Duration, resync: Duration, restart: Duration) -> Thresholds {
+        Thresholds {
+            warn,
+            resync,
+            restart,
+        }
+    }
+
+    #[test]
+    fn nothing_due_before_the_first_frame() {
+        let mut watchdog =
+            Watchdog::new(thresholds(Duration::ZERO, Duration::ZERO, Duration::ZERO));
+        assert_eq!(watchdog.check(), Action::None);
+    }
+
+    #[test]
+    fn nothing_due_while_frames_change() {
+        let mut watchdog = Watchdog::new(thresholds(HOUR, HOUR, HOUR));
+        watchdog.frame(&[1; 100]);
+        assert_eq!(watchdog.check(), Action::None);
+    }
+
+    #[test]
+    fn each_step_is_taken_once() {
+        let mut watchdog = Watchdog::new(thresholds(Duration::ZERO, HOUR, HOUR));
+        watchdog.frame(&[1; 100]);
+        assert_eq!(watchdog.check(), Action::Warn);
+        assert_eq!(watchdog.check(), Action::None);
+        assert!(!watchdog.take_resync());
+    }
+
+    #[test]
+    fn escalates_while_frozen() {
+        let mut watchdog =
+            Watchdog::new(thresholds(Duration::ZERO, Duration::from_millis(20), HOUR));
+        watchdog.frame(&[1; 100]);
+        assert_eq!(watchdog.check(), Action::Warn);
+
+        thread::sleep(Duration::from_millis(30));
+        // A repeated picture does not count as a new frame
+        assert!(!watchdog.frame(&[1; 100]));
+        assert_eq!(watchdog.check(), Action::Resync);
+        assert!(watchdog.take_resync());
+        assert!(!watchdog.take_resync());
+    }
+
+    #[test]
+    fn skips_to_the_highest_due_step() {
+        let mut watchdog =
+            Watchdog::new(thresholds(Duration::ZERO, Duration::ZERO, Duration::ZERO));
+        watchdog.frame(&[1; 100]);
+        assert_eq!(watchdog.check(), Action::Restart);
+        assert_eq!(watchdog.check(), Action::None);
+    }
+
+    #[test]
+    fn new_frame_recovers_and_rearms() {
+        let mut watchdog = Watchdog::new(thresholds(Duration::ZERO, HOUR, HOUR));
+        assert!(!watchdog.frame(&[1; 100]));
+        assert_eq!(watchdog.check(), Action::Warn);
+        assert!(watchdog.frame(&[2; 100]));
+        assert_eq!(watchdog.check(), Action::Warn);
+    }
+
+    #[test]
+    fn any_changed_byte_is_a_new_frame() {
+        let mut watchdog = Watchdog::new(thresholds(Duration::ZERO, HOUR, HOUR));
+        let mut frame = vec![0u8; 1280 * 720 * 3];
+        watchdog.frame(&frame);
+        assert_eq!(watchdog.check(), Action::Warn);
+        // A single changed byte, like a small object moving in a still scene
+        frame[1000] = 1;
+        assert!(watchdog.frame(&frame));
+        assert!(!watchdog.frame(&frame));
+    }
+}
@@ -0,0 +1,328 @@
+//! WebRTC session with the robot.
+//!
+//! `go2webrtc_rs::run` does not report anything about the peer connection, so the
+//! session is pointed at local relay sockets instead of the decoder ports. The relays
+//! forward every RTP packet unchanged and use the first packets they see to report
+//! how far the connection got. The ICE state and the selected candidate pair stay
+//! inside go2webrtc-rs and are not reported.
+//!
+//! The video relay also depacketizes the H.264 stream it forwards, so the encoded
+//! access units are available without going through the decoder.
+//!
+//! When the session fails or is dropped the relays send an RTCP BYE to the decoder
+//! ports, which makes ffmpeg end the stream instead of blocking forever on a socket
+//! that will never see another packet.
+
+use anyhow::{bail, Result};
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, watch, Notify};
+use tokio::task::{self, JoinHandle};
+use tokio::time;
+
+use crate::h264::{AccessUnit, Depacketizer};
+
+/// Access units buffered per subscriber before slow subscribers start missing some.
+const ACCESS_UNIT_BUFFER: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SessionState {
+    /// Signalling with the robot is in progress.
+    Connecting,
+    /// The first RTP packet arrived on any track.
+    MediaReceived,
+    /// The first video RTP packet arrived and was forwarded to the decoder port.
+    VideoReceived { payload_type: u8 },
+    /// The session could not be established or has ended.
+    Failed(String),
+}
+
+impl SessionState {
+    fn rank(&self) -> u8 {
+        match self {
+            SessionState::Connecting => 0,
+            SessionState::MediaReceived => 1,
+            SessionState::VideoReceived { .. } => 2,
+            SessionState::Failed(_) => 3,
+        }
+    }
+}
+
+/// Connection settings for a single session.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub robot_ip: String,
+    pub robot_token: String,
+    /// Local port the decoder reads video RTP from.
+    pub video_port: u16,
+    /// Local port audio RTP is forwarded to.
+    pub audio_port: u16,
+    pub debug_webrtc: bool,
+    /// The session is considered failed when video stops for this long.
+    pub stall_timeout: Duration,
+}
+
+/// Video traffic seen by the relay, counted across sessions.
+#[derive(Default, Debug)]
+pub struct Traffic {
+    pub packets: AtomicU64,
+    pub bytes: AtomicU64,
+    /// Complete access units (frames) depacketized from the stream
+    pub access_units: AtomicU64,
+}
+
+pub struct Session {
+    state_tx: Arc<watch::Sender<SessionState>>,
+    state: watch::Receiver<SessionState>,
+    access_units: broadcast::Receiver<Arc<AccessUnit>>,
+    audio_payload_type: watch::Receiver<Option<u8>>,
+    end_video_stream: Arc<Notify>,
+    webrtc_task: JoinHandle<()>,
+}
+
+/// What a relay forwards, and where it reports what it sees.
+enum Track {
+    Video(broadcast::Sender<Arc<AccessUnit>>, Arc<Traffic>),
+    Audio(watch::Sender<Option<u8>>),
+}
+
+impl Session {
+    /// Binds the relay sockets and starts signalling with the robot.
+    ///
+    /// Video passing through the relay is counted in `traffic`.
+    pub async fn start(config: Config, traffic: Arc<Traffic>) -> Result<Session> {
+        let (state_tx, state) = watch::channel(SessionState::Connecting);
+        let state_tx = Arc::new(state_tx);
+        let (access_units_tx, access_units) = broadcast::channel(ACCESS_UNIT_BUFFER);
+        let (audio_payload_type_tx, audio_payload_type) = watch::channel(None);
+        let end_video_stream = Arc::new(Notify::new());
+
+        let video_relay = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let audio_relay = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let relay_video_port = video_relay.local_addr()?.port();
+        let relay_audio_port = audio_relay.local_addr()?.port();
+
+        task::spawn(relay(
+            video_relay,
+            (Ipv4Addr::LOCALHOST, config.video_port).into(),
+            Track::Video(access_units_tx, traffic),
+            Some(config.stall_timeout),
+            end_video_stream.clone(),
+            state_tx.clone(),
+        ));
+        task::spawn(relay(
+            audio_relay,
+            (Ipv4Addr::LOCALHOST, config.audio_port).into(),
+            Track::Audio(audio_payload_type_tx),
+            None,
+            Arc::new(Notify::new()),
+            state_tx.clone(),
+        ));
+
+        let webrtc_task = task::spawn({
+            let state_tx = state_tx.clone();
+            async move {
+                let result = go2webrtc_rs::run(
+                    relay_video_port,
+                    relay_audio_port,
+                    &config.robot_ip,
+                    &config.robot_token,
+                    config.debug_webrtc,
+                )
+                .await;
+
+                let reason = match result {
+                    Ok(_) => "WebRTC session closed".to_string(),
+                    Err(e) => format!("WebRTC session error: {}", e),
+                };
+                advance(&state_tx, SessionState::Failed(reason));
+            }
+        });
+
+        Ok(Session {
+            state_tx,
+            state,
+            access_units,
+            audio_payload_type,
+            end_video_stream,
+            webrtc_task,
+        })
+    }
+
+    /// Returns a receiver that observes every state change of the session.
+    pub fn state(&self) -> watch::Receiver<SessionState> {
+        self.state.clone()
+    }
+
+    /// Subscribes to the encoded video; the channel closes when the session ends.
+    pub fn access_units(&self) -> broadcast::Receiver<Arc<AccessUnit>> {
+        self.access_units.resubscribe()
+    }
+
+    /// Ends the RTP stream at the video port with a BYE while the session keeps running.
+    ///
+    /// The decoder reading that port returns, and one opened afterwards starts over
+    /// from the next keyframe.
+    pub fn end_video_stream(&self) {
+        self.end_video_stream.notify_one();
+    }
+
+    /// Waits until audio RTP is flowing to the audio port, for as long as the session lives.
+    ///
+    /// Returns the RTP payload type the robot sends audio with.
+    pub fn wait_for_audio(&self) -> impl Future<Output = Result<u8>> + Send + 'static {
+        let mut payload_type = self.audio_payload_type.clone();
+        let mut state = self.state.clone();
+        async move {
+            tokio::select! {
+                received = payload_type.wait_for(Option::is_some) => match received {
+                    Ok(payload_type) => Ok(payload_type.unwrap()),
+                    Err(_) => bail!("session ended before audio was received"),
+                },
+                _ = state.wait_for(|s| matches!(s, SessionState::Failed(_))) => {
+                    bail!("session ended before audio was received")
+                }
+            }
+        }
+    }
+
+    /// Waits until video RTP is flowing to the decoder port.
+    ///
+    /// Returns the RTP payload type the robot sends video with.
+    pub async fn wait_for_video(&mut self, timeout: Duration) -> Result<u8> {
+        let ready = self.state.wait_for(|s| {
+            matches!(
+                s,
+                SessionState::VideoReceived { .. } | SessionState::Failed(_)
+            )
+        });
+
+        match time::timeout(timeout, ready).await {
+            Err(_) => bail!(
+                "no video received from the robot within {:.1}s",
+                timeout.as_secs_f64()
+            ),
+            Ok(Err(_)) => bail!("WebRTC session task exited unexpectedly"),
+            Ok(Ok(state)) => match &*state {
+                SessionState::VideoReceived { payload_type } => Ok(*payload_type),
+                SessionState::Failed(reason) => bail!("{}", reason),
+                _ => unreachable!(),
+            },
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // The relays notice the state change, send their BYEs and exit on their own
+        advance(
+            &self.state_tx,
+            SessionState::Failed("session stopped".to_string()),
+        );
+        self.webrtc_task.abort();
+    }
+}
+
+/// Moves the session state forward; a session never goes back to an earlier state.
+fn advance(state: &watch::Sender<SessionState>, next: SessionState) {
+    state.send_if_modified(|current| {
+        if next.rank() > current.rank() {
+            *current = next;
+            true
+        } else {
+            false
+        }
+    });
+}
+
+/// Builds an RTCP BYE for `ssrc`.
+fn rtcp_bye(ssrc: [u8; 4]) -> [u8; 8] {
+    // V=2, one source, PT=203 (BYE), length of one 32-bit word after the header
+    [0x81, 203, 0x00, 0x01, ssrc[0], ssrc[1], ssrc[2], ssrc[3]]
+}
+
+async fn relay(
+    socket: UdpSocket,
+    target: SocketAddr,
+    track: Track,
+    stall_timeout: Option<Duration>,
+    end_stream: Arc<Notify>,
+    state: Arc<watch::Sender<SessionState>>,
+) {
+    let mut failed = state.subscribe();
+    let mut buf = vec![0u8; 65536];
+    let mut ssrc: Option<[u8; 4]> = None;
+    let mut depacketizer = Depacketizer::new();
+
+    loop {
+        // The stall timer only runs once the stream has started
+        let timeout = match (ssrc, stall_timeout) {
+            (Some(_), Some(timeout)) => timeout,
+            _ => Duration::MAX,
+        };
+
+        tokio::select! {
+            received = time::timeout(timeout, socket.recv_from(&mut buf)) => {
+                let len = match received {
+                    Ok(Ok((len, _))) => len,
+                    Ok(Err(e)) => {
+                        advance(&state, SessionState::Failed(format!("RTP relay error: {}", e)));
+                        break;
+                    }
+                    Err(_) => {
+                        advance(
+                            &state,
+                            SessionState::Failed(format!(
+                                "no video from the robot for {:.1}s",
+                                timeout.as_secs_f64()
+                            )),
+                        );
+                        break;
+                    }
+                };
+
+                // RTCP sender reports share the port, the payload type tells them apart
+                if ssrc.is_none() && len >= 12 && !(200..=204).contains(&buf[1]) {
+                    ssrc = Some([buf[8], buf[9], buf[10], buf[11]]);
+                    advance(&state, SessionState::MediaReceived);
+                    let payload_type = buf[1] & 0x7f;
+                    match &track {
+                        Track::Video(..) => {
+                            advance(&state, SessionState::VideoReceived { payload_type })
+                        }
+                        Track::Audio(audio_payload_type) => {
+                            audio_payload_type.send_replace(Some(payload_type));
+                        }
+                    }
+                }
+
+                // Nobody listening on the target yet is not an error, the packet is just lost
+                let _ = socket.send_to(&buf[..len], target).await;
+
+                if let Track::Video(access_units, traffic) = &track {
+                    traffic.packets.fetch_add(1, Ordering::Relaxed);
+                    traffic.bytes.fetch_add(len as u64, Ordering::Relaxed);
+                    depacketizer.push(&buf[..len], |access_unit| {
+                        traffic.access_units.fetch_add(1, Ordering::Relaxed);
+                        // No subscribers is fine, the access unit is just not needed
+                        let _ = access_units.send(Arc::new(access_unit));
+                    });
+                }
+            }
+            _ = end_stream.notified() => {
+                if let Some(ssrc) = ssrc {
+                    let _ = socket.send_to(&rtcp_bye(ssrc), target).await;
+                }
+            }
+            _ = failed.wait_for(|s| matches!(s, SessionState::Failed(_))) => break,
+        }
+    }
+
+    if let Some(ssrc) = ssrc {
+        let _ = socket.send_to(&rtcp_bye(ssrc), target).await;
+    }
+}